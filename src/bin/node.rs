@@ -1,6 +1,10 @@
 use slog::{Logger, Drain, Level};
 use logging::file::FileAppenderBuilder;
-use tezedge_bootstrap_poc::Socket;
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use tezedge_bootstrap_poc::{
+    Socket, Config, SharedChain, HeaderStore, TrustedCheckpoint, MempoolObserver, decode, dump,
+    export,
+};
 
 fn create_logger() -> Logger {
     let _ = std::fs::remove_file("target/_.log");
@@ -20,10 +24,166 @@ fn create_logger() -> Logger {
     Logger::root(drain, slog::o!())
 }
 
+const USAGE: &str = "\
+usage: node [--checkpoint <hash> <level>] [--follow <interval seconds>]
+            [--mempool <ttl seconds>] [--disable-mempool] [--private-node] <address>...
+       node decode pcap <capture.pcap> [identity.json]
+       node decode streams <initiator> <responder> [identity.json]
+       node dump inspect <data.dump|export> [json|csv]
+       node dump convert <data.dump> <export>";
+
+fn decode(args: &[String]) {
+    let identity = |i: usize| args.get(i).map(String::as_str).unwrap_or("identity.json");
+    let events = match args {
+        [kind, capture, ..] if kind == "pcap" => decode::decode_pcap(capture, identity(2)),
+        [kind, initiator, responder, ..] if kind == "streams" => {
+            decode::decode_streams(initiator, responder, identity(3))
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+    };
+    match events {
+        Ok(events) => {
+            for event in events {
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+        },
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn dump(args: &[String]) {
+    let (path, format) = match args {
+        [command, dump, target] if command == "convert" => {
+            match export::convert_dump(dump, target) {
+                Ok(count) => println!("converted {} headers", count),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                },
+            }
+            return;
+        },
+        [command, path] if command == "inspect" => (path, None),
+        [command, path, format] if command == "inspect" => (path, Some(format.as_str())),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+    };
+    let headers = export::read_headers(path).unwrap_or_else(|error| exit(error, 1));
+    let stdout = std::io::stdout();
+    let result = match format {
+        None => dump::Summary::new(&headers).map(|summary| println!("{}", summary)),
+        Some("json") => dump::write_json(stdout.lock(), &headers),
+        Some("csv") => dump::write_csv(stdout.lock(), &headers),
+        Some(_) => exit(USAGE, 2),
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+/// Prints the error and exits, wrong arguments exit with the code 2
+fn exit<E>(error: E, code: i32) -> !
+where
+    E: fmt::Display,
+{
+    eprintln!("{}", error);
+    std::process::exit(code)
+}
+
+fn parse<T>(value: &str, what: &str) -> T
+where
+    T: FromStr,
+{
+    value
+        .parse()
+        .unwrap_or_else(|_| exit(format!("invalid {} {}\n{}", what, value, USAGE), 2))
+}
+
+async fn run(args: &[String]) {
+    let mut config = Config {
+        export: Some(PathBuf::from("target/data.export")),
+        ..Config::default()
+    };
+    let mut checkpoint = None;
+    let mut mempool = None;
+    let mut args = args;
+    let addresses = loop {
+        args = match args {
+            [flag, hash, level, rest @ ..] if flag == "--checkpoint" => {
+                let hash = hex::decode(hash)
+                    .unwrap_or_else(|_| exit(format!("invalid hash {}\n{}", hash, USAGE), 2));
+                checkpoint = Some(TrustedCheckpoint {
+                    hash: hash,
+                    level: parse(level, "level"),
+                });
+                rest
+            },
+            [flag, interval, rest @ ..] if flag == "--follow" => {
+                config.follow = Some(Duration::from_secs(parse(interval, "interval")));
+                rest
+            },
+            [flag, ttl, rest @ ..] if flag == "--mempool" => {
+                mempool = Some(MempoolObserver::new(Duration::from_secs(parse(ttl, "ttl"))));
+                rest
+            },
+            [flag, rest @ ..] if flag == "--disable-mempool" => {
+                config.disable_mempool = true;
+                rest
+            },
+            [flag, rest @ ..] if flag == "--private-node" => {
+                config.private_node = true;
+                rest
+            },
+            [flag, ..] if flag.starts_with("--") => exit(USAGE, 2),
+            addresses => break addresses,
+        };
+    };
+    let addresses = addresses
+        .iter()
+        .map(|address| parse::<SocketAddr>(address, "address"))
+        .collect::<Vec<_>>();
+
+    // continue from the previous run, only the new headers are downloaded
+    let store = HeaderStore::open("target/store", 256).unwrap_or_else(|error| exit(error, 1));
+    // the stored headers are checked against the checkpoint as well
+    let mut shared =
+        SharedChain::from_store(store, checkpoint).unwrap_or_else(|error| exit(error, 1));
+    if let Some(mempool) = mempool {
+        shared = shared.with_mempool(mempool);
+    }
+
+    let logger = create_logger();
+    let handles = addresses
+        .into_iter()
+        .map(|address| {
+            let (mut socket, _) = Socket::outgoing(address, config.clone(), shared.clone());
+            let logger = logger.new(slog::o!("peer" => address.to_string()));
+            tokio::spawn(async move { socket.run(&logger).await })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        if let Err(error) = handle.await.unwrap() {
+            slog::error!(logger, "{}", error);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let logger = create_logger();
-    let address = std::env::args().nth(1).unwrap();
-    let (mut socket, _) = Socket::outgoing(address.parse().unwrap());
-    socket.run(&logger).await.unwrap();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("decode") => decode(&args[1..]),
+        Some("dump") => dump(&args[1..]),
+        Some(_) => run(&args).await,
+        None => eprintln!("{}", USAGE),
+    }
 }
@@ -1,24 +1,509 @@
-use std::collections::BTreeMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use tezos_messages::p2p::{
     binary_message::BinaryMessage,
     encoding::block_header::BlockHeader,
 };
 use crypto::{blake2b, hash::Hash};
+use super::SocketError;
 
+/// Number of hashes in the locator between doublings of the step
+const LOCATOR_STEP_INTERVAL: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Level {
+    /// the level is exact
     Precise(u32),
+    /// only the hash is known, for example from the history of the remote branch
     Guess(u32),
 }
 
-pub enum Block {
-    Hash(Hash),
-    Header(BlockHeader),
+impl Level {
+    fn value(&self) -> u32 {
+        match self {
+            &Level::Precise(level) => level,
+            &Level::Guess(level) => level,
+        }
+    }
 }
 
+/// Contiguous segment of headers, the lowest level goes first
 pub struct HeadersChain {
-    blocks: Vec<BlockHeader>,
+    blocks: VecDeque<BlockHeader>,
+    hashes: VecDeque<Hash>,
+}
+
+impl HeadersChain {
+    fn new(header: BlockHeader, hash: Hash) -> Self {
+        HeadersChain {
+            blocks: VecDeque::from(vec![header]),
+            hashes: VecDeque::from(vec![hash]),
+        }
+    }
+
+    fn lowest(&self) -> &BlockHeader {
+        self.blocks.front().unwrap()
+    }
+
+    fn highest_hash(&self) -> &Hash {
+        self.hashes.back().unwrap()
+    }
+
+    /// The hash of the block right below the segment, none if the segment starts at the genesis
+    fn missing(&self) -> Option<&Hash> {
+        if self.lowest().level() == 0 {
+            None
+        } else {
+            Some(self.lowest().predecessor())
+        }
+    }
+
+    fn get(&self, level: u32) -> Option<(&Hash, &BlockHeader)> {
+        let lowest = self.lowest().level() as u32;
+        if level < lowest {
+            return None;
+        }
+        let i = (level - lowest) as usize;
+        self.hashes.get(i).and_then(|h| self.blocks.get(i).map(|b| (h, b)))
+    }
+
+    fn push_front(&mut self, header: BlockHeader, hash: Hash) {
+        self.blocks.push_front(header);
+        self.hashes.push_front(hash);
+    }
+
+    fn push_back(&mut self, header: BlockHeader, hash: Hash) {
+        self.blocks.push_back(header);
+        self.hashes.push_back(hash);
+    }
+
+    fn append(&mut self, mut other: HeadersChain) {
+        self.blocks.append(&mut other.blocks);
+        self.hashes.append(&mut other.hashes);
+    }
 }
 
+/// Headers received so far, grouped into contiguous segments, plus hashes of blocks
+/// which are known to be in the chain, but have no header yet
 pub struct BlockChain {
+    /// ordered by the lowest level
     sequence: Vec<HeadersChain>,
+    /// levels of blocks whose header is in the sequence
+    known: HashMap<Hash, u32>,
+    /// guessed levels of blocks known only by hash
+    guesses: HashMap<Hash, u32>,
+    /// nothing below this level is needed
+    checkpoint: Option<u32>,
+}
+
+impl BlockChain {
+    pub fn new() -> Self {
+        BlockChain {
+            sequence: Vec::new(),
+            known: HashMap::new(),
+            guesses: HashMap::new(),
+            checkpoint: None,
+        }
+    }
+
+    /// The chain starts at the level instead of the genesis
+    pub fn set_checkpoint(&mut self, level: u32) {
+        self.checkpoint = Some(level);
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.known.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&BlockHeader> {
+        let level = *self.known.get(hash)?;
+        self.sequence
+            .iter()
+            .filter_map(|s| s.get(level))
+            .find(|&(h, _)| h == hash)
+            .map(|(_, header)| header)
+    }
+
+    /// Headers from the block down to the genesis, or down to the first unknown block,
+    /// which is below the checkpoint if the chain has one
+    pub fn branch(&self, hash: &Hash) -> Vec<BlockHeader> {
+        let mut headers = Vec::new();
+        let mut current = self.get(hash);
+        while let Some(header) = current {
+            headers.push(header.clone());
+            current = if header.level() == 0 {
+                None
+            } else {
+                self.get(header.predecessor())
+            };
+        }
+        headers
+    }
+
+    /// The hash of the block `distance` levels below the block,
+    /// none if some header in between is unknown
+    fn ancestor<'a>(&'a self, mut hash: &'a Hash, mut distance: u32) -> Option<&'a Hash> {
+        loop {
+            if distance == 0 {
+                return Some(hash);
+            }
+            let level = *self.known.get(hash)?;
+            let segment = self.segment(hash)?;
+            let lowest = segment.lowest().level() as u32;
+            if level - lowest >= distance {
+                return segment.get(level - distance).map(|(h, _)| h);
+            }
+            // go to the block right below the segment
+            distance -= level - lowest + 1;
+            hash = segment.missing()?;
+        }
+    }
+
+    /// The lowest block of both branches, with its level,
+    /// none if some header in between is unknown
+    pub fn common_ancestor<'a>(
+        &'a self,
+        mut a: &'a Hash,
+        mut b: &'a Hash,
+    ) -> Option<(Hash, i32)> {
+        loop {
+            let (a_level, b_level) = (*self.known.get(a)?, *self.known.get(b)?);
+            if a == b {
+                return Some((a.clone(), a_level as i32));
+            }
+            if a_level >= b_level {
+                a = self.predecessor(a)?;
+            } else {
+                b = self.predecessor(b)?;
+            }
+        }
+    }
+
+    /// The segment which contains the block
+    fn segment(&self, hash: &Hash) -> Option<&HeadersChain> {
+        let level = *self.known.get(hash)?;
+        self.sequence
+            .iter()
+            .find(|s| s.get(level).map(|(h, _)| h) == Some(hash))
+    }
+
+    fn predecessor(&self, hash: &Hash) -> Option<&Hash> {
+        let header = self.get(hash)?;
+        if header.level() == 0 {
+            None
+        } else {
+            Some(header.predecessor())
+        }
+    }
+
+    /// Hashes below the block, the step between them starts at one and doubles
+    /// after every `LOCATOR_STEP_INTERVAL` hashes, the genesis is not included
+    pub fn locator(&self, hash: &Hash, size: usize) -> Vec<Hash> {
+        let mut history = Vec::new();
+        let mut current = hash;
+        let mut step = 1;
+        while history.len() < size {
+            match self.ancestor(current, step) {
+                Some(ancestor) if self.known.get(ancestor) != Some(&0) => {
+                    history.push(ancestor.clone());
+                    current = ancestor;
+                },
+                _ => break,
+            }
+            if history.len() % LOCATOR_STEP_INTERVAL == 0 {
+                step *= 2;
+            }
+        }
+        history
+    }
+
+    /// The header whose predecessor is the `hash`, if it is the lowest in some segment
+    pub fn child(&self, hash: &Hash) -> Option<&BlockHeader> {
+        self.sequence
+            .iter()
+            .find(|s| s.missing() == Some(hash))
+            .map(HeadersChain::lowest)
+    }
+
+    pub fn parent(&self, header: &BlockHeader) -> Option<&BlockHeader> {
+        if header.level() == 0 {
+            None
+        } else {
+            self.get(header.predecessor())
+        }
+    }
+
+    /// Remember the block known only by hash, does nothing if the block is already known
+    pub fn insert_hash(&mut self, hash: Hash, level: u32) {
+        if !self.known.contains_key(&hash) {
+            self.guesses.entry(hash).or_insert(level);
+        }
+    }
+
+    /// Forgets the block known only by hash, the header is kept if it is known
+    pub fn remove_hash(&mut self, hash: &Hash) {
+        self.guesses.remove(hash);
+    }
+
+    /// Inserts the header in any order, merges segments once they link,
+    /// returns false if the header is already there
+    pub fn insert(&mut self, header: BlockHeader) -> Result<bool, SocketError> {
+        let hash = block_hash(&header)?;
+        if self.contains(&hash) {
+            return Ok(false);
+        }
+        self.guesses.remove(&hash);
+        self.known.insert(hash.clone(), header.level() as u32);
+
+        let lower = if header.level() == 0 {
+            None
+        } else {
+            self.sequence
+                .iter()
+                .position(|s| s.highest_hash() == header.predecessor())
+        };
+        let upper = self
+            .sequence
+            .iter()
+            .position(|s| s.missing() == Some(&hash));
+        match (lower, upper) {
+            (Some(lower), Some(upper)) => {
+                self.sequence[lower].push_back(header, hash);
+                let segment = self.sequence.remove(upper);
+                let lower = if upper < lower { lower - 1 } else { lower };
+                self.sequence[lower].append(segment);
+            },
+            (Some(lower), None) => self.sequence[lower].push_back(header, hash),
+            (None, Some(upper)) => self.sequence[upper].push_front(header, hash),
+            (None, None) => {
+                let level = header.level();
+                let i = self
+                    .sequence
+                    .iter()
+                    .position(|s| s.lowest().level() > level)
+                    .unwrap_or(self.sequence.len());
+                self.sequence.insert(i, HeadersChain::new(header, hash));
+            },
+        }
+        Ok(true)
+    }
+
+    /// Hashes of blocks which are still needed below the roots, the highest level goes first,
+    /// blocks below the checkpoint are not needed
+    pub fn gaps<'a, I>(&self, roots: I) -> Vec<(Hash, Level)>
+    where
+        I: IntoIterator<Item = &'a Hash>,
+    {
+        let mut visited = HashSet::new();
+        let mut gaps = roots
+            .into_iter()
+            .filter_map(|root| self.gap_below(root, &mut visited))
+            .collect::<Vec<_>>();
+        gaps.sort_by(|(_, a), (_, b)| b.value().cmp(&a.value()));
+        gaps
+    }
+
+    /// Goes down from the block through the segments, returns the first block
+    /// which is not known, stops at the block visited by another root
+    fn gap_below<'a>(
+        &'a self,
+        mut hash: &'a Hash,
+        visited: &mut HashSet<&'a Hash>,
+    ) -> Option<(Hash, Level)> {
+        let lowest = self.checkpoint.unwrap_or(0);
+        let mut child = None;
+        loop {
+            if !visited.insert(hash) {
+                return None;
+            }
+            let segment = match self.segment(hash) {
+                Some(segment) => segment,
+                None => break,
+            };
+            let level = segment.lowest().level() as u32;
+            if level <= lowest {
+                // the genesis or the checkpoint
+                return None;
+            }
+            hash = segment.missing()?;
+            child = Some(level);
+        }
+        let level = match (child, self.guesses.get(hash)) {
+            (Some(child), _) => Level::Precise(child - 1),
+            (None, Some(&level)) if level >= lowest => Level::Guess(level),
+            _ => return None,
+        };
+        Some((hash.clone(), level))
+    }
+
+    /// Nothing is missing below the head, so it leads to the genesis or to the checkpoint,
+    /// the branch might go through several segments, if the chain was known before
+    /// and the head is on a fork
+    pub fn is_complete(&self, head: &Hash) -> bool {
+        self.contains(head) && self.gaps(Some(head)).is_empty()
+    }
+}
+
+/// The hash of the block, the genesis block is its own predecessor
+pub fn block_hash(header: &BlockHeader) -> Result<Hash, SocketError> {
+    if header.level() == 0 {
+        return Ok(header.predecessor().clone());
+    }
+    header
+        .as_bytes()
+        .map(|bytes| blake2b::digest_256(bytes.as_ref()))
+        .map_err(|_| SocketError::EncodingError)
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_messages::p2p::encoding::block_header::BlockHeader;
+    use crypto::hash::Hash;
+    use super::{BlockChain, Level, block_hash};
+    use crate::testing::{self, ChainGenerator};
+
+    fn hashes(headers: &[BlockHeader]) -> Vec<Hash> {
+        headers.iter().map(|header| block_hash(header).unwrap()).collect()
+    }
+
+    fn insert(chain: &mut BlockChain, headers: &[BlockHeader]) {
+        for header in headers {
+            assert!(chain.insert(header.clone()).unwrap());
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let headers = testing::chain(10);
+        let hashes = hashes(&headers);
+        let mut chain = BlockChain::new();
+        for &i in &[5, 2, 9, 0, 7, 3, 8, 1, 6, 4] {
+            assert!(!chain.is_complete(&hashes[9]));
+            assert!(chain.insert(headers[i].clone()).unwrap());
+        }
+        assert!(!chain.insert(headers[5].clone()).unwrap());
+        assert!(chain.is_complete(&hashes[9]));
+        for (hash, header) in hashes.iter().zip(&headers) {
+            assert_eq!(chain.get(hash).map(BlockHeader::level), Some(header.level()));
+        }
+        assert_eq!(chain.branch(&hashes[9]).len(), 10);
+    }
+
+    #[test]
+    fn merge_segments() {
+        let headers = testing::chain(10);
+        let mut chain = BlockChain::new();
+        insert(&mut chain, &headers[6..]);
+        insert(&mut chain, &headers[..3]);
+        assert_eq!(chain.sequence.len(), 2);
+        // the segment grows down from above and up from below
+        insert(&mut chain, &headers[5..6]);
+        insert(&mut chain, &headers[3..4]);
+        assert_eq!(chain.sequence.len(), 2);
+        // the header links both segments
+        insert(&mut chain, &headers[4..5]);
+        assert_eq!(chain.sequence.len(), 1);
+        assert_eq!(chain.sequence[0].lowest().level(), 0);
+        assert_eq!(chain.sequence[0].highest_hash(), &block_hash(&headers[9]).unwrap());
+    }
+
+    #[test]
+    fn gaps() {
+        let headers = testing::chain(16);
+        let hashes = hashes(&headers);
+        let mut chain = BlockChain::new();
+        insert(&mut chain, &headers[15..]);
+        // the history tells only that the block is at most at the level
+        chain.insert_hash(hashes[8].clone(), 9);
+        let roots = [hashes[15].clone(), hashes[8].clone()];
+        let expected = vec![
+            (hashes[14].clone(), Level::Precise(14)),
+            (hashes[8].clone(), Level::Guess(9)),
+        ];
+        assert_eq!(chain.gaps(&roots), expected);
+
+        insert(&mut chain, &headers[8..9]);
+        let expected = vec![
+            (hashes[14].clone(), Level::Precise(14)),
+            (hashes[7].clone(), Level::Precise(7)),
+        ];
+        assert_eq!(chain.gaps(&roots), expected);
+
+        // the segments of both roots lead to the same gap once they merge
+        insert(&mut chain, &headers[9..15]);
+        let expected = vec![(hashes[7].clone(), Level::Precise(7))];
+        assert_eq!(chain.gaps(&roots), expected);
+        assert!(!chain.is_complete(&hashes[15]));
+
+        // nothing is needed below the checkpoint
+        chain.set_checkpoint(8);
+        assert!(chain.gaps(&roots).is_empty());
+        assert!(chain.is_complete(&hashes[15]));
+    }
+
+    #[test]
+    fn gaps_of_other_branch() {
+        let headers = testing::chain(12);
+        let fork = ChainGenerator::new(0).seed(1).fork(&headers, 6, 3);
+        let mut chain = BlockChain::new();
+        insert(&mut chain, &headers);
+        insert(&mut chain, &fork[9..]);
+        let head = block_hash(&headers[11]).unwrap();
+        assert!(chain.is_complete(&head));
+        let fork_head = block_hash(&fork[9]).unwrap();
+        let expected = vec![(block_hash(&fork[8]).unwrap(), Level::Precise(8))];
+        assert_eq!(chain.gaps(&[fork_head]), expected);
+    }
+
+    #[test]
+    fn common_ancestor() {
+        let headers = testing::chain(12);
+        let fork = ChainGenerator::new(0).seed(1).fork(&headers, 6, 3);
+        let head = block_hash(&headers[11]).unwrap();
+        let fork_head = block_hash(&fork[9]).unwrap();
+        let base = block_hash(&headers[6]).unwrap();
+        let mut chain = BlockChain::new();
+        insert(&mut chain, &headers);
+        insert(&mut chain, &fork[8..]);
+        // the header in between is unknown
+        assert_eq!(chain.common_ancestor(&head, &fork_head), None);
+        insert(&mut chain, &fork[7..8]);
+        assert_eq!(chain.common_ancestor(&head, &fork_head), Some((base.clone(), 6)));
+        assert_eq!(chain.common_ancestor(&fork_head, &head), Some((base, 6)));
+        assert_eq!(chain.common_ancestor(&head, &head), Some((head.clone(), 11)));
+    }
+
+    #[test]
+    fn locator() {
+        let headers = testing::chain(32);
+        let hashes = hashes(&headers);
+        let mut chain = BlockChain::new();
+        insert(&mut chain, &headers[20..]);
+        // the size is the limit
+        let expected = hashes[28..31].iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(chain.locator(&hashes[31], 3), expected);
+        // ten blocks with the step of one, then the predecessor of the lowest known header
+        let locator = chain.locator(&hashes[31], 100);
+        assert_eq!(locator.len(), 11);
+        assert_eq!(locator.last(), Some(&hashes[19]));
+    }
+
+    #[test]
+    fn locator_step() {
+        let headers = testing::chain(63);
+        let mut chain = BlockChain::new();
+        insert(&mut chain, &headers);
+        let head = block_hash(&headers[62]).unwrap();
+        let levels = chain
+            .locator(&head, 100)
+            .iter()
+            .map(|hash| chain.get(hash).unwrap().level())
+            .collect::<Vec<_>>();
+        // the step doubles after every ten hashes, the last step lands on the genesis
+        let expected = (52..62)
+            .rev()
+            .chain((32..51).rev().step_by(2))
+            .chain((4..29).rev().step_by(4))
+            .collect::<Vec<_>>();
+        assert_eq!(levels, expected);
+    }
 }
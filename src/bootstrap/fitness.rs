@@ -0,0 +1,14 @@
+use std::cmp::Ordering;
+use tezos_messages::p2p::encoding::block_header::Fitness;
+
+/// Tezos fitness ordering, the longer list is greater, then elements are compared one by one,
+/// the longer element is greater, elements of the same length are compared as bytes
+pub fn compare(a: &Fitness, b: &Fitness) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| {
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)))
+            .find(|&o| o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    })
+}
@@ -0,0 +1,122 @@
+use std::{net::SocketAddr, time::Duration};
+use slog::Logger;
+use tezos_messages::p2p::encoding::{
+    prelude::*,
+    peer::{PeerMessageResponse, PeerMessage},
+};
+use super::{
+    SocketError,
+    Config,
+    TrustedConnection,
+    ChainId,
+    block_hash,
+    message::MAX_REQUEST_LENGTH,
+    responder::Responder,
+    shared_chain::SharedChain,
+    sync_block_headers::SyncBlockHeaders,
+    sync_operations::SyncOperations,
+};
+
+/// Keeps the chain at the head of the remote peer after the bootstrap,
+/// learns about new blocks from `CurrentHead`, both unsolicited and requested,
+/// and about operations in the mempool of the peer if the chain observes the mempool
+/// and the peer has not disabled its own
+pub struct Follow {
+    peer: SocketAddr,
+    chain_id: ChainId,
+    shared: SharedChain,
+    config: Config,
+    interval: Duration,
+}
+
+impl Follow {
+    pub fn new(
+        peer: SocketAddr,
+        chain_id: ChainId,
+        shared: SharedChain,
+        config: &Config,
+        interval: Duration,
+    ) -> Self {
+        Follow {
+            peer: peer,
+            chain_id: chain_id,
+            shared: shared,
+            config: config.clone(),
+            interval: interval,
+        }
+    }
+
+    pub async fn run(
+        &mut self,
+        connection: &mut TrustedConnection<PeerMessageResponse>,
+        responder: &mut Responder,
+        logger: &Logger,
+    ) -> Result<(), SocketError> {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            let r = tokio::select! {
+                r = connection.read() => r?,
+                _ = interval.tick() => {
+                    let request = GetCurrentHeadMessage::new(self.chain_id.to_vec());
+                    connection.write(&request.into()).await?;
+                    continue;
+                },
+            };
+            let mut new_head = None;
+            let mut unknown_operations = Vec::new();
+            let disable_mempool = responder.disable_mempool();
+            for message in r.messages() {
+                match message {
+                    &PeerMessage::CurrentHead(ref m) if m.chain_id() == &self.chain_id => {
+                        let header = m.current_block_header();
+                        if !self.shared.contains(&block_hash(header)?) {
+                            new_head = Some(header.clone());
+                        }
+                        // the mempool of the peer which has disabled it is unsolicited
+                        let mempool = self.shared.mempool().filter(|_| !disable_mempool);
+                        if let Some(mempool) = mempool {
+                            let m = m.current_mempool();
+                            let hashes = m.known_valid().iter().chain(m.pending()).cloned();
+                            unknown_operations.extend(mempool.announce(self.peer, hashes));
+                        }
+                    },
+                    // operations are requested only from the mempool of the peer
+                    &PeerMessage::Operation(ref m) => {
+                        let mempool = self.shared.mempool().filter(|_| !disable_mempool);
+                        if let Some(mempool) = mempool {
+                            mempool.observe(self.peer, m.operation())?;
+                        }
+                    },
+                    // the peer which lies about the body of a block is dropped
+                    &PeerMessage::OperationHashesForBlock(ref m) => {
+                        self.shared.check_operation_hashes(m)?
+                    },
+                    _ => (),
+                }
+            }
+            if !unknown_operations.is_empty() {
+                let messages = unknown_operations
+                    .chunks(MAX_REQUEST_LENGTH)
+                    .map(|hashes| GetOperationsMessage::new(hashes.to_vec()).into())
+                    .collect::<Vec<_>>();
+                connection.write_batch(&messages).await?;
+            }
+            let to_write = responder.handle(&r, logger);
+            if !to_write.is_empty() {
+                connection.write_batch(to_write.as_ref()).await?;
+            }
+
+            if let Some(head) = new_head {
+                slog::info!(logger, "new head at level {}", head.level());
+                // download the headers between our chain and the new head, then the operations
+                let branch = CurrentBranch::new(head, Vec::new());
+                SyncBlockHeaders::new(branch, self.shared.clone(), &self.config)
+                    .run(connection, responder, logger)
+                    .await?;
+                SyncOperations::new(self.shared.clone(), &self.config)
+                    .run(connection, responder, logger)
+                    .await?;
+            }
+        }
+    }
+}
@@ -1,5 +1,32 @@
 use tezos_messages::p2p::encoding::block_header::BlockHeader;
-use super::ChainId;
+use crypto::hash::Hash;
+use super::{ChainId, SyncError};
+
+/// Trusted block, the sync starts from it instead of the genesis,
+/// and accepts only branches which contain it
+#[derive(Clone, Debug)]
+pub struct TrustedCheckpoint {
+    pub hash: Hash,
+    pub level: i32,
+}
+
+impl TrustedCheckpoint {
+    /// The block at the level of the checkpoint must be the checkpoint
+    pub fn check(&self, header: &BlockHeader, hash: &Hash) -> Result<(), SyncError> {
+        if header.level() == self.level && hash != &self.hash {
+            return Err(SyncError::Checkpoint { level: self.level });
+        }
+        Ok(())
+    }
+
+    /// The branch which ends at the head might contain the checkpoint
+    pub fn check_head(&self, head: &BlockHeader) -> Result<(), SyncError> {
+        if head.level() < self.level {
+            return Err(SyncError::Checkpoint { level: self.level });
+        }
+        Ok(())
+    }
+}
 
 pub fn block_header() -> BlockHeader {
     use tezos_messages::p2p::encoding::block_header::BlockHeaderBuilder;
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tezos_messages::p2p::encoding::operation::Operation;
+use crypto::hash::Hash;
+use super::{SocketError, merkle};
+
+/// Operation seen in the mempool of some peer
+#[derive(Clone)]
+pub struct Observed {
+    /// none until the peer sends the operation itself, the mempool carries only hashes
+    pub operation: Option<Operation>,
+    /// the peer which announced the operation first
+    pub peer: SocketAddr,
+    pub first_seen: Instant,
+}
+
+/// Operations in the mempools of all peers, deduplicated by the hash,
+/// an operation is forgotten once `ttl` passes since it was first seen
+#[derive(Clone)]
+pub struct MempoolObserver {
+    inner: Arc<Mutex<HashMap<Hash, Observed>>>,
+    ttl: Duration,
+}
+
+impl MempoolObserver {
+    pub fn new(ttl: Duration) -> Self {
+        MempoolObserver {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            ttl: ttl,
+        }
+    }
+
+    /// Remembers hashes from the mempool of the peer,
+    /// returns hashes seen for the first time, their operations should be requested
+    pub fn announce<I>(&self, peer: SocketAddr, hashes: I) -> Vec<Hash>
+    where
+        I: IntoIterator<Item = Hash>,
+    {
+        self.announce_at(peer, hashes, Instant::now())
+    }
+
+    fn announce_at<I>(&self, peer: SocketAddr, hashes: I, now: Instant) -> Vec<Hash>
+    where
+        I: IntoIterator<Item = Hash>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        self.expire(&mut inner, now);
+        hashes
+            .into_iter()
+            .filter(|hash| {
+                if inner.contains_key(hash) {
+                    return false;
+                }
+                let observed = Observed {
+                    operation: None,
+                    peer: peer,
+                    first_seen: now,
+                };
+                inner.insert(hash.clone(), observed);
+                true
+            })
+            .collect()
+    }
+
+    /// Remembers the operation sent by the peer, returns false if it is already known
+    pub fn observe(&self, peer: SocketAddr, operation: &Operation) -> Result<bool, SocketError> {
+        self.observe_at(peer, operation, Instant::now())
+    }
+
+    fn observe_at(
+        &self,
+        peer: SocketAddr,
+        operation: &Operation,
+        now: Instant,
+    ) -> Result<bool, SocketError> {
+        let hash = merkle::operation_hash(operation)?;
+        let mut inner = self.inner.lock().unwrap();
+        self.expire(&mut inner, now);
+        let observed = inner.entry(hash).or_insert_with(|| Observed {
+            operation: None,
+            peer: peer,
+            first_seen: now,
+        });
+        if observed.operation.is_some() {
+            return Ok(false);
+        }
+        observed.operation = Some(operation.clone());
+        Ok(true)
+    }
+
+    /// The operations which are not expired yet
+    pub fn live(&self) -> Vec<(Hash, Observed)> {
+        self.live_at(Instant::now())
+    }
+
+    fn live_at(&self, now: Instant) -> Vec<(Hash, Observed)> {
+        let mut inner = self.inner.lock().unwrap();
+        self.expire(&mut inner, now);
+        inner
+            .iter()
+            .map(|(hash, observed)| (hash.clone(), observed.clone()))
+            .collect()
+    }
+
+    fn expire(&self, inner: &mut HashMap<Hash, Observed>, now: Instant) {
+        let ttl = self.ttl;
+        inner.retain(|_, observed| now.duration_since(observed.first_seen) < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+    use super::MempoolObserver;
+    use crate::testing;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn announce() {
+        let mempool = MempoolObserver::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let first = mempool.announce_at(peer(1), vec![vec![1; 32], vec![2; 32]], now);
+        assert_eq!(first, vec![vec![1; 32], vec![2; 32]]);
+        // only the new hash is requested, the first peer stays the source
+        let second = mempool.announce_at(peer(2), vec![vec![2; 32], vec![3; 32]], now);
+        assert_eq!(second, vec![vec![3; 32]]);
+        let live = mempool.live_at(now);
+        let (_, observed) = live.iter().find(|(hash, _)| hash == &vec![2; 32]).unwrap();
+        assert_eq!(observed.peer, peer(1));
+        assert!(observed.operation.is_none());
+    }
+
+    #[test]
+    fn observe() {
+        let mempool = MempoolObserver::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let (hash, operation) = testing::operation(&vec![0; 32], b"operation");
+        assert_eq!(mempool.announce_at(peer(1), vec![hash.clone()], now), vec![hash.clone()]);
+        assert!(mempool.observe_at(peer(2), &operation, now).unwrap());
+        // the same operation from another peer is a duplicate
+        assert!(!mempool.observe_at(peer(3), &operation, now).unwrap());
+        let live = mempool.live_at(now);
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].0, hash);
+        assert_eq!(live[0].1.peer, peer(1));
+        assert!(live[0].1.operation.is_some());
+    }
+
+    #[test]
+    fn expire() {
+        let mempool = MempoolObserver::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let (hash, operation) = testing::operation(&vec![0; 32], b"operation");
+        assert!(mempool.observe_at(peer(1), &operation, start).unwrap());
+        mempool.announce_at(peer(1), vec![vec![1; 32]], start + Duration::from_secs(30));
+        assert_eq!(mempool.live_at(start + Duration::from_secs(59)).len(), 2);
+        // the ttl counts from the first sight
+        let live = mempool.live_at(start + Duration::from_secs(60));
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].0, vec![1; 32]);
+        // the expired operation is new again
+        let later = start + Duration::from_secs(120);
+        assert!(mempool.live_at(later).is_empty());
+        assert_eq!(mempool.announce_at(peer(2), vec![hash.clone()], later), vec![hash]);
+        assert!(mempool.observe_at(peer(2), &operation, later).unwrap());
+    }
+}
@@ -0,0 +1,153 @@
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        block_header::BlockHeader,
+        operation::Operation,
+        operations_for_blocks::{OperationsForBlocksMessage, Path, PathLeft, PathRight},
+        operation_hashes_for_blocks::OperationHashesForBlocksMessage,
+    },
+};
+use crypto::{blake2b, hash::Hash};
+use super::{SocketError, SyncError};
+
+/// Root of the Tezos merkle tree, the leaves are hashed and padded up to a power of two
+/// with the last leaf, a single leaf gives its hash, no leaves give the hash of nothing
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.last() {
+        None => blake2b::digest_256(&[]),
+        Some(last) if leaves.len() == 1 => leaf(last),
+        Some(last) => node(leaves, last, leaves.len().next_power_of_two()),
+    }
+}
+
+/// The `operations_hash` of the block header, computed over the operations of each pass
+pub fn operations_hash(passes: &[Hash]) -> Hash {
+    root(passes)
+}
+
+/// The hash of the operations of one validation pass
+pub fn operation_list_hash(operations: &[Operation]) -> Result<Hash, SocketError> {
+    let hashes = operations
+        .iter()
+        .map(operation_hash)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(root(&hashes))
+}
+
+pub fn operation_hash(operation: &Operation) -> Result<Hash, SocketError> {
+    operation
+        .as_bytes()
+        .map(|bytes| blake2b::digest_256(bytes.as_ref()))
+        .map_err(|_| SocketError::EncodingError)
+}
+
+/// Path from the root down to the leaf at the `index`, the leaves must not be empty
+pub fn path(leaves: &[Hash], index: usize) -> Path {
+    let size = leaves.len().next_power_of_two();
+    path_node(leaves, leaves.last().unwrap(), size, index)
+}
+
+/// Computes the root from the leaf and its path,
+/// returns the root, the number of leaves padded to a power of two and the index of the leaf
+pub fn check_path(path: &Path, leaf: &Hash) -> (Hash, usize, usize) {
+    match path {
+        &Path::Op => (self::leaf(leaf), 1, 0),
+        &Path::Left(ref left) => {
+            let (hash, size, index) = check_path(left.path(), leaf);
+            (pair(&hash, left.right()), size * 2, index)
+        },
+        &Path::Right(ref right) => {
+            let (hash, size, index) = check_path(right.path(), leaf);
+            (pair(right.left(), &hash), size * 2, index + size)
+        },
+    }
+}
+
+/// Checks the operations of one validation pass against the header using the path
+pub fn check_operations(
+    header: &BlockHeader,
+    message: &OperationsForBlocksMessage,
+) -> Result<(), SocketError> {
+    let leaf = operation_list_hash(message.operations())?;
+    let validation_pass = *message.operations_for_block().validation_pass();
+    check_pass(header, message.operation_hashes_path(), &leaf, validation_pass)
+}
+
+/// Checks the operation hashes of one validation pass against the header using the path
+pub fn check_operation_hashes(
+    header: &BlockHeader,
+    message: &OperationHashesForBlocksMessage,
+) -> Result<(), SocketError> {
+    let leaf = root(message.operation_hashes());
+    let validation_pass = *message.operation_hashes_for_block().validation_pass();
+    check_pass(header, message.operation_hashes_path(), &leaf, validation_pass)
+}
+
+fn check_pass(
+    header: &BlockHeader,
+    path: &Path,
+    leaf: &Hash,
+    validation_pass: i8,
+) -> Result<(), SocketError> {
+    let level = header.level();
+    let passes = header.validation_pass() as usize;
+    let (root, size, index) = check_path(path, leaf);
+    if validation_pass < 0 || index != validation_pass as usize || index >= passes {
+        let error = SyncError::OperationsPath {
+            level: level,
+            validation_pass: validation_pass,
+        };
+        return Err(SocketError::Sync(error));
+    }
+    if &root != header.operations_hash() || size != passes.next_power_of_two() {
+        return Err(SocketError::Sync(SyncError::OperationsHash { level: level }));
+    }
+    Ok(())
+}
+
+fn leaf(value: &[u8]) -> Hash {
+    blake2b::digest_256(value)
+}
+
+fn pair(left: &[u8], right: &[u8]) -> Hash {
+    blake2b::digest_256(&[left, right].concat())
+}
+
+/// The subtree over `size` leaves, the missing leaves are the `last`
+fn node(leaves: &[Hash], last: &Hash, size: usize) -> Hash {
+    if leaves.is_empty() {
+        return padding(last, size);
+    }
+    if size == 1 {
+        return leaf(&leaves[0]);
+    }
+    let half = size / 2;
+    let (left, right) = leaves.split_at(half.min(leaves.len()));
+    pair(&node(left, last, half), &node(right, last, half))
+}
+
+fn path_node(leaves: &[Hash], last: &Hash, size: usize, index: usize) -> Path {
+    if size == 1 {
+        return Path::Op;
+    }
+    let half = size / 2;
+    let (left, right) = leaves.split_at(half.min(leaves.len()));
+    if index < half {
+        let right = node(right, last, half);
+        let path = path_node(left, last, half, index);
+        Path::Left(Box::new(PathLeft::new(path, right, Default::default())))
+    } else {
+        let left = node(left, last, half);
+        let path = path_node(right, last, half, index - half);
+        Path::Right(Box::new(PathRight::new(left, path, Default::default())))
+    }
+}
+
+fn padding(last: &Hash, size: usize) -> Hash {
+    if size == 1 {
+        leaf(last)
+    } else {
+        let half = padding(last, size / 2);
+        pair(&half, &half)
+    }
+}
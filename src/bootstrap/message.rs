@@ -1,7 +1,12 @@
 use tezos_messages::p2p::encoding::prelude::*;
 
+/// The encoding bounds every list of hashes in a request,
+/// a node cannot decode a longer request and drops the connection
+pub const MAX_REQUEST_LENGTH: usize = 10;
+
 #[derive(Debug)]
 pub enum Request<'a> {
+    Bootstrap,
     GetCurrentBranch(&'a GetCurrentBranchMessage),
     GetCurrentHead(&'a GetCurrentHeadMessage),
     GetBlockHeaders(&'a GetBlockHeadersMessage),
@@ -14,6 +19,7 @@ pub enum Request<'a> {
 impl<'a> Request<'a> {
     pub fn filter(m: &'a PeerMessageResponse) -> impl Iterator<Item = Request<'a>> {
         m.messages().iter().filter_map(|m| match m {
+            &PeerMessage::Bootstrap => Some(Request::Bootstrap),
             &PeerMessage::GetCurrentBranch(ref m) => Some(Request::GetCurrentBranch(m)),
             &PeerMessage::GetCurrentHead(ref m) => Some(Request::GetCurrentHead(m)),
             &PeerMessage::GetBlockHeaders(ref m) => Some(Request::GetBlockHeaders(m)),
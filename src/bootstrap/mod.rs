@@ -1,14 +1,36 @@
-use super::{error::SocketError, trusted_connection::TrustedConnection};
+use super::{
+    error::{SocketError, SyncError},
+    config::Config,
+    trusted_connection::TrustedConnection,
+};
 
 pub type ChainId = [u8; 4];
 
 pub mod genesis;
+pub use self::genesis::TrustedCheckpoint;
 
 mod message;
+pub use self::message::MAX_REQUEST_LENGTH;
+
+mod fitness;
+mod validate;
+pub mod merkle;
+
+mod shared_chain;
+pub use self::shared_chain::{SharedChain, Reorg};
+
+mod responder;
 
 mod sync_block_headers;
-#[allow(warnings)]
+pub use self::sync_block_headers::{read_dump, write_dump};
+mod sync_operations;
+mod sync_protocols;
+pub use self::sync_protocols::protocol_hash;
+mod follow;
+mod mempool;
+pub use self::mempool::{MempoolObserver, Observed};
 mod blockchain;
+pub use self::blockchain::block_hash;
 
 mod state;
 pub use self::state::BootstrapState;
@@ -0,0 +1,327 @@
+use std::{convert::TryFrom, net::SocketAddr, time::Instant};
+use slog::Logger;
+use tezos_messages::p2p::encoding::{
+    prelude::*,
+    peer::PeerMessageResponse,
+    metadata::MetadataMessage,
+};
+use super::{
+    SocketError,
+    Config,
+    ChainId,
+    genesis,
+    merkle,
+    message::Request,
+    shared_chain::SharedChain,
+};
+
+/// Maximal number of hashes in the history of our current branch
+const HISTORY_SIZE: usize = 200;
+
+/// Answers requests of the remote peer from the headers and the operations we hold,
+/// each header, operation or pass goes in a message of its own, the connection cuts
+/// the message into chunks of `CONTENT_LENGTH_MAX`, requests we cannot answer are skipped
+pub struct Responder {
+    peer: SocketAddr,
+    chain_id: ChainId,
+    shared: SharedChain,
+    limit: RateLimit,
+    /// the peer has disabled its mempool, explicit requests for operations are still served
+    disable_mempool: bool,
+}
+
+impl Responder {
+    /// The `metadata` is received from the `peer`
+    pub fn new(
+        peer: SocketAddr,
+        chain_id: ChainId,
+        shared: SharedChain,
+        config: &Config,
+        metadata: &MetadataMessage,
+    ) -> Self {
+        Responder {
+            peer: peer,
+            chain_id: chain_id,
+            shared: shared,
+            limit: RateLimit::new(config.serve_rate, config.serve_burst),
+            disable_mempool: metadata.disable_mempool(),
+        }
+    }
+
+    /// The peer neither sends nor wants mempool traffic
+    pub fn disable_mempool(&self) -> bool {
+        self.disable_mempool
+    }
+
+    pub fn handle(
+        &mut self,
+        message: &PeerMessageResponse,
+        logger: &Logger,
+    ) -> Vec<PeerMessageResponse> {
+        let mut write = Vec::new();
+        for request in Request::filter(message) {
+            match request {
+                Request::Bootstrap => {
+                    // private peers are never in the list
+                    let peers = self
+                        .shared
+                        .public_peers()
+                        .into_iter()
+                        .filter(|&address| address != self.peer)
+                        .collect::<Vec<_>>();
+                    if !peers.is_empty() {
+                        write.push(AdvertiseMessage::new(&peers).into());
+                    }
+                },
+                Request::GetCurrentBranch(m) => {
+                    if ChainId::try_from(m.chain_id.clone()).ok() == Some(self.chain_id) {
+                        // only the genesis until the sync is done
+                        let current_branch = self
+                            .shared
+                            .current_branch(HISTORY_SIZE)
+                            .unwrap_or_else(|| {
+                                CurrentBranch::new(genesis::block_header(), Vec::new())
+                            });
+                        let response =
+                            CurrentBranchMessage::new(self.chain_id.to_vec(), current_branch);
+                        write.push(response.into())
+                    }
+                },
+                Request::GetBlockHeaders(m) => {
+                    for hash in m.get_block_headers() {
+                        let header = match self.shared.get(hash) {
+                            Some(header) => header,
+                            // the peer will ask somebody else
+                            None => continue,
+                        };
+                        if !self.limit.take() {
+                            slog::debug!(logger, "rate limited, drop the rest of the request");
+                            break;
+                        }
+                        write.push(BlockHeaderMessage::from(header).into());
+                    }
+                },
+                Request::GetOperations(m) => {
+                    for hash in m.get_operations() {
+                        let operation = match self.shared.operation(hash) {
+                            Some(operation) => operation,
+                            None => continue,
+                        };
+                        if !self.limit.take() {
+                            slog::debug!(logger, "rate limited, drop the rest of the request");
+                            break;
+                        }
+                        write.push(OperationMessage::from(operation).into());
+                    }
+                },
+                Request::GetOperationHashesForBlocks(m) => {
+                    for block in m.get_operation_hashes_for_blocks() {
+                        let validation_pass = *block.validation_pass();
+                        let response = match self
+                            .shared
+                            .operations(block.hash(), validation_pass)
+                            .map(|operations| operation_hashes(&operations))
+                        {
+                            Some(Ok(response)) => response,
+                            _ => continue,
+                        };
+                        if !self.limit.take() {
+                            slog::debug!(logger, "rate limited, drop the rest of the request");
+                            break;
+                        }
+                        write.push(response.into());
+                    }
+                },
+                Request::GetOperationsForBlocks(m) => {
+                    for block in m.get_operations_for_blocks() {
+                        let validation_pass = *block.validation_pass();
+                        let response = match self.shared.operations(block.hash(), validation_pass) {
+                            Some(response) => response,
+                            None => continue,
+                        };
+                        if !self.limit.take() {
+                            slog::debug!(logger, "rate limited, drop the rest of the request");
+                            break;
+                        }
+                        write.push(response.into());
+                    }
+                },
+                r => slog::warn!(logger, "ignored message {:x?}", r),
+            }
+        }
+        write
+    }
+}
+
+/// Hashes of the operations of the pass, with the path of the pass they were stored with
+fn operation_hashes(
+    operations: &OperationsForBlocksMessage,
+) -> Result<OperationHashesForBlocksMessage, SocketError> {
+    let block = operations.operations_for_block();
+    let hashes = operations
+        .operations()
+        .iter()
+        .map(merkle::operation_hash)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(OperationHashesForBlocksMessage::new(
+        OperationHashesForBlock::new(block.hash().clone(), *block.validation_pass()),
+        operations.operation_hashes_path().clone(),
+        hashes,
+    ))
+}
+
+/// Token bucket, a token per served header, operation or pass
+struct RateLimit {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    fn new(rate: u32, burst: u32) -> Self {
+        RateLimit {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tezos_messages::p2p::encoding::{
+        prelude::*,
+        peer::PeerMessageResponse,
+        metadata::MetadataMessage,
+    };
+    use super::{Responder, RateLimit};
+    use crate::{
+        Config,
+        HeaderStore,
+        bootstrap::{genesis, block_hash, merkle, shared_chain::SharedChain},
+        testing,
+    };
+
+    fn responder(config: &Config) -> (Responder, Vec<BlockHeader>) {
+        let headers = testing::chain(4);
+        let shared = SharedChain::from_headers(headers.clone()).unwrap();
+        let peer = "127.0.0.1:9732".parse().unwrap();
+        let metadata = MetadataMessage::new(false, false);
+        let responder = Responder::new(peer, genesis::CHAIN_ID, shared, config, &metadata);
+        (responder, headers)
+    }
+
+    fn served(responses: &[PeerMessageResponse]) -> Vec<i32> {
+        responses
+            .iter()
+            .flat_map(|response| response.messages())
+            .filter_map(|message| match message {
+                &PeerMessage::BlockHeader(ref m) => Some(m.block_header().level()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headers() {
+        let (mut responder, headers) = responder(&Config::default());
+        let hashes = vec![
+            block_hash(&headers[2]).unwrap(),
+            vec![0x55; 32],
+            block_hash(&headers[3]).unwrap(),
+        ];
+        let request: PeerMessageResponse = GetBlockHeadersMessage::new(hashes).into();
+        let responses = responder.handle(&request, &testing::logger());
+        // the unknown header is skipped
+        assert_eq!(served(&responses), vec![2, 3]);
+    }
+
+    #[test]
+    fn rate_limited_headers() {
+        let config = Config {
+            serve_rate: 0,
+            serve_burst: 2,
+            ..Config::default()
+        };
+        let (mut responder, headers) = responder(&config);
+        let hashes = headers
+            .iter()
+            .map(|header| block_hash(header).unwrap())
+            .collect::<Vec<_>>();
+        let request: PeerMessageResponse = GetBlockHeadersMessage::new(hashes).into();
+        let responses = responder.handle(&request, &testing::logger());
+        assert_eq!(served(&responses), vec![0, 1]);
+        assert!(served(&responder.handle(&request, &testing::logger())).is_empty());
+    }
+
+    #[test]
+    fn operations_without_mempool() {
+        let path = std::env::temp_dir().join(format!("responder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let headers = testing::chain(2);
+        let hash = block_hash(&headers[1]).unwrap();
+        let (operation_hash, operation) = testing::operation(&hash, b"operation");
+        let mut store = HeaderStore::open(&path, 1).unwrap();
+        for header in &headers {
+            store.append(header).unwrap();
+        }
+        let leaves = vec![merkle::operation_list_hash(&[operation.clone()]).unwrap()];
+        let operations = OperationsForBlocksMessage::new(
+            OperationsForBlock::new(hash, 0),
+            merkle::path(&leaves, 0),
+            vec![operation],
+        );
+        store.append_operations(&operations).unwrap();
+        let shared = SharedChain::from_store(store, None).unwrap();
+
+        // the peer has disabled its mempool, but asks for the operation explicitly
+        let peer = "127.0.0.1:9732".parse().unwrap();
+        let metadata = MetadataMessage::new(true, false);
+        let config = Config::default();
+        let mut responder = Responder::new(peer, genesis::CHAIN_ID, shared, &config, &metadata);
+        let request: PeerMessageResponse = GetOperationsMessage::new(vec![operation_hash]).into();
+        let responses = responder.handle(&request, &testing::logger());
+        let served = responses
+            .iter()
+            .flat_map(|response| response.messages())
+            .filter(|message| matches!(message, &PeerMessage::Operation(_)))
+            .count();
+        assert_eq!(served, 1);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut limit = RateLimit::new(10, 3);
+        let start = limit.last;
+        assert!((0..3).all(|_| limit.take_at(start)));
+        assert!(!limit.take_at(start));
+        // a token per 100 milliseconds
+        assert!(!limit.take_at(start + Duration::from_millis(50)));
+        assert!(limit.take_at(start + Duration::from_millis(150)));
+        assert!(!limit.take_at(start + Duration::from_millis(150)));
+        // the bucket does not grow over the burst
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| limit.take_at(later)));
+        assert!(!limit.take_at(later));
+    }
+}
@@ -0,0 +1,901 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use tezos_messages::p2p::encoding::{
+    block_header::BlockHeader,
+    current_branch::CurrentBranch,
+    operation::Operation,
+    operations_for_blocks::OperationsForBlocksMessage,
+    operation_hashes_for_blocks::OperationHashesForBlocksMessage,
+    protocol::Protocol,
+};
+use crypto::hash::Hash;
+use super::{
+    SocketError,
+    SyncError,
+    block_hash,
+    fitness,
+    merkle,
+    validate,
+    mempool::MempoolObserver,
+    sync_protocols::activated_protocol,
+    genesis::TrustedCheckpoint,
+    blockchain::{BlockChain, Level},
+    super::{
+        export::ExportWriter,
+        storage::{HeaderStore, Checkpoint},
+    },
+};
+
+/// Number of accepted headers between checkpoints
+const CHECKPOINT_INTERVAL: usize = 1024;
+
+/// The chain assembled by all peers, each peer leases gaps of the chain and downloads them,
+/// a lease expires if the peer is too slow, and is released if the peer fails
+#[derive(Clone)]
+pub struct SharedChain {
+    inner: Arc<Mutex<Inner>>,
+    complete: watch::Receiver<bool>,
+    operations_complete: watch::Receiver<bool>,
+    mempool: Option<MempoolObserver>,
+}
+
+/// The canonical head moved to another branch
+#[derive(Clone, Debug)]
+pub struct Reorg {
+    pub old_head: Hash,
+    pub new_head: Hash,
+    /// number of blocks of the old branch which are no longer canonical
+    pub depth: u32,
+}
+
+/// Operations ordered by the level, with the peer and the deadline of the lease
+type PendingOperations = BTreeMap<(i32, Hash, i8), Option<(usize, Instant)>>;
+
+struct Inner {
+    chain: BlockChain,
+    /// the sync does not go below the checkpoint
+    checkpoint: Option<TrustedCheckpoint>,
+    /// the fittest remote head, or the local tip, the canonical chain goes from it
+    head: Option<(Hash, i32)>,
+    /// hashes from the history of the head, gaps below them are downloaded in parallel
+    anchors: Vec<Hash>,
+    /// the head whose completion is announced
+    completed: Option<Hash>,
+    /// the head before the switch and the new head, resolved once the chain is complete
+    pending_reorg: Option<(Hash, Hash)>,
+    reorgs: Vec<Reorg>,
+    /// headers are persisted as they arrive
+    store: Option<HeaderStore>,
+    /// the peer and the deadline
+    leases: HashMap<Hash, (usize, Instant)>,
+    peers: usize,
+    complete: watch::Sender<bool>,
+    taken: bool,
+    accepted: usize,
+    /// operations of the canonical branch to download, collected once the chain is complete
+    pending_operations: Option<PendingOperations>,
+    /// the head whose branch the pending operations are collected for
+    operations_head: Option<Hash>,
+    operations_complete: watch::Sender<bool>,
+    /// protocols to download, with the peer and the deadline of the lease
+    pending_protocols: HashMap<Hash, Option<(usize, Instant)>>,
+    /// downloaded protocols
+    protocols: HashSet<Hash>,
+    /// protocols by the `proto` level, known from the configuration
+    activations: HashMap<u8, Hash>,
+    /// `proto` levels the chain switches to
+    protocol_changes: BTreeSet<u8>,
+    /// connected peers which might be advertised to other peers
+    public_peers: HashSet<SocketAddr>,
+}
+
+impl SharedChain {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        let (operations_tx, operations_rx) = watch::channel(false);
+        SharedChain {
+            inner: Arc::new(Mutex::new(Inner {
+                chain: BlockChain::new(),
+                checkpoint: None,
+                head: None,
+                anchors: Vec::new(),
+                completed: None,
+                pending_reorg: None,
+                reorgs: Vec::new(),
+                store: None,
+                leases: HashMap::new(),
+                peers: 0,
+                complete: tx,
+                taken: false,
+                accepted: 0,
+                pending_operations: None,
+                operations_head: None,
+                operations_complete: operations_tx,
+                pending_protocols: HashMap::new(),
+                protocols: HashSet::new(),
+                activations: HashMap::new(),
+                protocol_changes: BTreeSet::new(),
+                public_peers: HashSet::new(),
+            })),
+            complete: rx,
+            operations_complete: operations_rx,
+            mempool: None,
+        }
+    }
+
+    /// Starts from the chain known locally, the sync stops once it reaches these headers
+    pub fn from_headers<I>(headers: I) -> Result<Self, SocketError>
+    where
+        I: IntoIterator<Item = BlockHeader>,
+    {
+        let shared = SharedChain::new();
+        {
+            let mut inner = shared.inner.lock().unwrap();
+            for header in headers {
+                let hash = block_hash(&header)?;
+                inner.chain.insert(header.clone())?;
+                inner.update_head(hash, &header);
+            }
+            // the local chain is the starting point, not a reorganization
+            inner.pending_reorg = None;
+        }
+        Ok(shared)
+    }
+
+    /// Starts from the headers in the store, new headers are appended to the store,
+    /// continues the interrupted sync if the store has a checkpoint
+    ///
+    /// Stored headers are validated again, also against the `checkpoint`, those which fail
+    /// are left out of the chain and forgotten by the store, so they are downloaded again
+    pub fn from_store(
+        mut store: HeaderStore,
+        checkpoint: Option<TrustedCheckpoint>,
+    ) -> Result<Self, SocketError> {
+        let shared = SharedChain::new();
+        {
+            let mut inner = shared.inner.lock().unwrap();
+            if let Some(checkpoint) = checkpoint {
+                inner.chain.set_checkpoint(checkpoint.level as u32);
+                inner.checkpoint = Some(checkpoint);
+            }
+            let mut invalid = Vec::new();
+            for header in store.range(..) {
+                let header = header.map_err(SocketError::Storage)?;
+                let hash = block_hash(&header)?;
+                if inner.validate(&header).is_ok() {
+                    inner.discover_protocol(&header, &hash);
+                    inner.chain.insert(header.clone())?;
+                    inner.update_head(hash, &header);
+                } else {
+                    invalid.push(hash);
+                }
+            }
+            for hash in &invalid {
+                store.forget(hash);
+            }
+            inner.pending_reorg = None;
+            let target = match store.checkpoint() {
+                Some(Checkpoint { head, anchors }) => {
+                    for (hash, level) in anchors {
+                        inner.chain.insert_hash(hash.clone(), level);
+                        inner.anchors.push(hash);
+                    }
+                    Some(head)
+                },
+                None => store.tip().cloned(),
+            };
+            if let Some(hash) = target {
+                if let Some(header) = inner.chain.get(&hash) {
+                    let level = header.level();
+                    inner.head = Some((hash, level));
+                }
+            }
+            inner
+                .pending_protocols
+                .retain(|hash, _| !store.contains_protocol(hash));
+            inner.store = Some(store);
+        }
+        Ok(shared)
+    }
+
+    /// Starts the sync from the trusted block instead of the genesis,
+    /// branches which do not contain the block are rejected
+    pub fn with_checkpoint(self, checkpoint: TrustedCheckpoint) -> Self {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.chain.set_checkpoint(checkpoint.level as u32);
+            inner.checkpoint = Some(checkpoint);
+        }
+        self
+    }
+
+    /// Observes mempools of the peers after the bootstrap,
+    /// without the observer the peers are asked not to send their mempool
+    pub fn with_mempool(mut self, mempool: MempoolObserver) -> Self {
+        self.mempool = Some(mempool);
+        self
+    }
+
+    pub fn mempool(&self) -> Option<&MempoolObserver> {
+        self.mempool.as_ref()
+    }
+
+    /// Identifier of a new peer
+    pub fn register(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.peers += 1;
+        inner.peers
+    }
+
+    /// Leases at most `limit` gaps to the peer, skips gaps leased by other peers
+    /// unless their lease is expired
+    pub fn claim(&self, peer: usize, limit: usize, timeout: Duration) -> Vec<Hash> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let now = Instant::now();
+        let gaps = inner.gaps();
+        let leases = &mut inner.leases;
+        let claimed = gaps
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .filter(|hash| match leases.get(hash) {
+                Some(&(_, deadline)) => deadline < now,
+                None => true,
+            })
+            .take(limit)
+            .collect::<Vec<_>>();
+        for hash in &claimed {
+            leases.insert(hash.clone(), (peer, now + timeout));
+        }
+        claimed
+    }
+
+    /// Releases leases of the peer, so other peers can take them
+    pub fn release<'a, I>(&self, peer: usize, hashes: I)
+    where
+        I: IntoIterator<Item = &'a Hash>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        for hash in hashes {
+            if let Some(&(owner, _)) = inner.leases.get(hash) {
+                if owner == peer {
+                    inner.leases.remove(hash);
+                }
+            }
+        }
+    }
+
+    /// The connected peer is not private, so it might be advertised
+    pub fn add_public_peer(&self, address: SocketAddr) {
+        self.inner.lock().unwrap().public_peers.insert(address);
+    }
+
+    pub fn remove_public_peer(&self, address: &SocketAddr) {
+        self.inner.lock().unwrap().public_peers.remove(address);
+    }
+
+    pub fn public_peers(&self) -> Vec<SocketAddr> {
+        let inner = self.inner.lock().unwrap();
+        inner.public_peers.iter().cloned().collect()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.inner.lock().unwrap().chain.contains(hash)
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<BlockHeader> {
+        self.inner.lock().unwrap().chain.get(hash).cloned()
+    }
+
+    /// Validates the header against its neighbors and inserts it, a known header is ignored
+    pub fn accept(&self, header: &BlockHeader) -> Result<(), SocketError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let hash = block_hash(header)?;
+        inner.leases.remove(&hash);
+        if inner.chain.contains(&hash) {
+            // duplicate of the header accepted before
+            return Ok(());
+        }
+
+        inner.validate(header)?;
+        if let Some(successor) = inner.chain.child(&hash) {
+            validate::link(successor, header).map_err(SocketError::Sync)?;
+        }
+        inner.discover_protocol(header, &hash);
+        inner.chain.insert(header.clone())?;
+        if let Some(store) = &mut inner.store {
+            store.append(header).map_err(SocketError::Storage)?;
+        }
+        inner.accepted += 1;
+
+        if inner.is_complete() {
+            inner.on_complete()?;
+        } else if inner.accepted % CHECKPOINT_INTERVAL == 0 {
+            inner.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Accepts the current head of the remote branch with hashes from its history,
+    /// the `levels` of the hashes are guesses, the fittest head is the target of the sync,
+    /// hashes of other heads are dropped
+    ///
+    /// With the checkpoint only the hashes above it in the history are kept, the guesses
+    /// are upper bounds, so other hashes might be below the checkpoint
+    pub fn accept_head<I>(&self, header: &BlockHeader, anchors: I) -> Result<(), SocketError>
+    where
+        I: IntoIterator<Item = (Hash, u32)>,
+    {
+        let hash = block_hash(header)?;
+        if let Some(checkpoint) = &self.inner.lock().unwrap().checkpoint {
+            checkpoint.check_head(header).map_err(SocketError::Sync)?;
+        }
+        if !self.contains(&hash) {
+            self.accept(header)?;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.update_head(hash.clone(), header);
+        if inner.head.as_ref().map(|(head, _)| head) == Some(&hash) {
+            let mut anchors = anchors.into_iter().collect::<Vec<_>>();
+            if let Some(checkpoint) = &inner.checkpoint {
+                let position = anchors.iter().position(|(anchor, _)| anchor == &checkpoint.hash);
+                match position {
+                    Some(position) => {
+                        anchors.truncate(position);
+                        anchors.push((checkpoint.hash.clone(), checkpoint.level as u32));
+                    },
+                    None => anchors.clear(),
+                }
+            }
+            for (anchor, level) in anchors {
+                if !inner.chain.contains(&anchor) && !inner.anchors.contains(&anchor) {
+                    inner.chain.insert_hash(anchor.clone(), level);
+                    inner.anchors.push(anchor);
+                }
+            }
+        }
+        if inner.is_complete() {
+            inner.on_complete()?;
+        } else {
+            // remember the target and the anchors, so the sync can be resumed
+            inner.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.inner.lock().unwrap().is_complete()
+    }
+
+    /// Resolves once the chain is complete
+    pub async fn completed(&self) {
+        let mut complete = self.complete.clone();
+        while !self.is_complete() {
+            if complete.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Our head and the locator below it, none until the chain is complete
+    pub fn current_branch(&self, history_size: usize) -> Option<CurrentBranch> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.is_complete() {
+            return None;
+        }
+        let (head, _) = inner.head.as_ref()?;
+        let header = inner.chain.get(head)?.clone();
+        let history = inner.chain.locator(head, history_size);
+        Some(CurrentBranch::new(header, history))
+    }
+
+    /// Leases at most `limit` validation passes whose operations are missing,
+    /// the chain must be complete
+    pub fn claim_operations(
+        &self,
+        peer: usize,
+        limit: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(Hash, i8)>, SocketError> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let pending = match inner.pending_operations()? {
+            Some(pending) => pending,
+            None => return Ok(Vec::new()),
+        };
+        Ok(pending
+            .iter_mut()
+            .filter(|(_, lease)| match lease {
+                &&mut Some((_, deadline)) => deadline < now,
+                &&mut None => true,
+            })
+            .take(limit)
+            .map(|((_, hash, validation_pass), lease)| {
+                *lease = Some((peer, now + timeout));
+                (hash.clone(), *validation_pass)
+            })
+            .collect())
+    }
+
+    /// Releases leases of the peer on operations
+    pub fn release_operations<'a, I>(&self, peer: usize, keys: I)
+    where
+        I: IntoIterator<Item = &'a (Hash, i8)>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let pending = match &mut inner.pending_operations {
+            Some(pending) => pending,
+            None => return,
+        };
+        for (hash, validation_pass) in keys {
+            let level = match inner.chain.get(hash) {
+                Some(header) => header.level(),
+                None => continue,
+            };
+            let key = (level, hash.clone(), *validation_pass);
+            if let Some(lease) = pending.get_mut(&key) {
+                if matches!(lease, &mut Some((owner, _)) if owner == peer) {
+                    *lease = None;
+                }
+            }
+        }
+    }
+
+    /// Checks the operations of the pass against the header using the path they carry,
+    /// they go to the store after the check
+    pub fn accept_operations(
+        &self,
+        operations: &OperationsForBlocksMessage,
+    ) -> Result<(), SocketError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let block = operations.operations_for_block();
+        let (hash, validation_pass) = (block.hash().clone(), *block.validation_pass());
+        let header = match inner.chain.get(&hash) {
+            Some(header) => header.clone(),
+            None => {
+                let error = SyncError::UnrequestedOperations {
+                    received: hash,
+                    validation_pass: validation_pass,
+                };
+                return Err(SocketError::Sync(error));
+            },
+        };
+        let key = (header.level(), hash.clone(), validation_pass);
+        let pending = inner.pending_operations()?;
+        if !pending.map_or(false, |pending| pending.contains_key(&key)) {
+            // duplicate of the operations received before
+            return Ok(());
+        }
+        // the peer will be dropped, its lease is released and somebody else gets the pass
+        merkle::check_operations(&header, operations)?;
+        if let Some(pending) = inner.pending_operations()? {
+            pending.remove(&key);
+        }
+        if let Some(store) = &mut inner.store {
+            store
+                .append_operations(operations)
+                .map_err(SocketError::Storage)?;
+        }
+
+        if inner.is_operations_complete()? {
+            if let Some(store) = &mut inner.store {
+                store.flush().map_err(SocketError::Storage)?;
+            }
+            let _ = inner.operations_complete.send(true);
+        }
+        Ok(())
+    }
+
+    pub fn is_operations_complete(&self) -> Result<bool, SocketError> {
+        self.inner.lock().unwrap().is_operations_complete()
+    }
+
+    /// Checks the operation hashes of the pass sent by a peer against the header using
+    /// the path they carry, hashes of an unknown block cannot be checked and pass
+    pub fn check_operation_hashes(
+        &self,
+        message: &OperationHashesForBlocksMessage,
+    ) -> Result<(), SocketError> {
+        match self.get(message.operation_hashes_for_block().hash()) {
+            Some(header) => merkle::check_operation_hashes(&header, message),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolves once the operations of every block are downloaded,
+    /// an error is left to `is_operations_complete` of the caller
+    pub async fn operations_completed(&self) {
+        let mut complete = self.operations_complete.clone();
+        while !self.is_operations_complete().unwrap_or(false) {
+            if complete.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Protocols which should be downloaded, known from elsewhere, for example from metadata
+    pub fn want_protocols<I>(&self, hashes: I)
+    where
+        I: IntoIterator<Item = Hash>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        for hash in hashes {
+            inner.want_protocol(hash);
+        }
+    }
+
+    /// Hashes of the protocols by the `proto` level,
+    /// the protocols are wanted once the chain switches to their levels
+    pub fn want_activations<I>(&self, activations: I)
+    where
+        I: IntoIterator<Item = (u8, Hash)>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        for (proto, hash) in activations {
+            if inner.protocol_changes.contains(&proto) {
+                inner.want_protocol(hash.clone());
+            }
+            inner.activations.insert(proto, hash);
+        }
+    }
+
+    /// `proto` levels the chain switches to whose protocols are not configured
+    pub fn unknown_activations(&self) -> Vec<u8> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .protocol_changes
+            .iter()
+            .filter(|proto| !inner.activations.contains_key(proto))
+            .cloned()
+            .collect()
+    }
+
+    /// Leases the protocols which are not yet downloaded and not leased by other peers
+    pub fn claim_protocols(&self, peer: usize, timeout: Duration) -> Vec<Hash> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .pending_protocols
+            .iter_mut()
+            .filter(|(_, lease)| match lease {
+                &&mut Some((_, deadline)) => deadline < now,
+                &&mut None => true,
+            })
+            .map(|(hash, lease)| {
+                *lease = Some((peer, now + timeout));
+                hash.clone()
+            })
+            .collect()
+    }
+
+    pub fn release_protocols<'a, I>(&self, peer: usize, hashes: I)
+    where
+        I: IntoIterator<Item = &'a Hash>,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        for hash in hashes {
+            if let Some(lease) = inner.pending_protocols.get_mut(hash) {
+                if matches!(lease, &mut Some((owner, _)) if owner == peer) {
+                    *lease = None;
+                }
+            }
+        }
+    }
+
+    /// Stored operations of the pass, none if they are not stored or cannot be read
+    pub fn operations(
+        &self,
+        hash: &Hash,
+        validation_pass: i8,
+    ) -> Option<OperationsForBlocksMessage> {
+        let inner = self.inner.lock().unwrap();
+        let store = inner.store.as_ref()?;
+        store.operations(hash, validation_pass).ok().flatten()
+    }
+
+    /// Stored operation by its hash
+    pub fn operation(&self, hash: &Hash) -> Option<Operation> {
+        let inner = self.inner.lock().unwrap();
+        let store = inner.store.as_ref()?;
+        store.operation(hash).ok().flatten()
+    }
+
+    /// The `hash` must be computed from the protocol by the caller
+    pub fn accept_protocol(&self, hash: &Hash, protocol: &Protocol) -> Result<(), SocketError> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pending_protocols.remove(hash).is_none() {
+            // duplicate of the protocol received before
+            return Ok(());
+        }
+        if let Some(store) = &mut inner.store {
+            store
+                .append_protocol(hash, protocol)
+                .map_err(SocketError::Storage)?;
+        }
+        inner.protocols.insert(hash.clone());
+        Ok(())
+    }
+
+    pub fn is_protocols_complete(&self) -> bool {
+        self.inner.lock().unwrap().pending_protocols.is_empty()
+    }
+
+    /// Reorganizations since the last call
+    pub fn take_reorgs(&self) -> Vec<Reorg> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.reorgs.drain(..).collect()
+    }
+
+    /// Exports the complete chain from the genesis, or from the checkpoint, to the head,
+    /// only the first caller writes, returns the number of written headers
+    ///
+    /// The branch is copied under the lock, the file is written without it,
+    /// so other peers are not blocked
+    pub fn export<P>(&self, path: P) -> Result<Option<usize>, SocketError>
+    where
+        P: AsRef<Path>,
+    {
+        let branch = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.is_complete() || inner.taken {
+                return Ok(None);
+            }
+            let (head, _) = match inner.head.clone() {
+                Some(head) => head,
+                None => return Ok(None),
+            };
+            inner.taken = true;
+            inner.chain.branch(&head)
+        };
+        let file = File::create(path).map_err(SocketError::Io)?;
+        let mut writer = ExportWriter::new(BufWriter::new(file)).map_err(SocketError::Export)?;
+        for header in branch.iter().rev() {
+            writer.push(header).map_err(SocketError::Export)?;
+        }
+        writer.finish().map_err(SocketError::Export)?;
+        Ok(Some(branch.len()))
+    }
+}
+
+impl Inner {
+    /// Checks the header against the clock and its predecessor
+    fn validate(&self, header: &BlockHeader) -> Result<(), SocketError> {
+        validate::timestamp(header).map_err(SocketError::Sync)?;
+        if let Some(predecessor) = self.chain.parent(header) {
+            validate::link(header, predecessor).map_err(SocketError::Sync)?;
+        }
+        if header.level() == 0 {
+            validate::genesis(header)?;
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.check(header, &block_hash(header)?).map_err(SocketError::Sync)?;
+        }
+        Ok(())
+    }
+
+    /// The operations which are not in the store, none while the chain is not complete,
+    /// so passes of a branch which is about to lose are not downloaded
+    fn pending_operations(&mut self) -> Result<Option<&mut PendingOperations>, SocketError> {
+        if !self.is_complete() {
+            return Ok(None);
+        }
+        if self.pending_operations.is_none() {
+            self.pending_operations = Some(BTreeMap::new());
+            if let Some((head, _)) = self.head.clone() {
+                for header in self.chain.branch(&head) {
+                    let hash = block_hash(&header)?;
+                    self.want_operations(&header, &hash);
+                }
+                self.operations_head = Some(head);
+            }
+        }
+        Ok(self.pending_operations.as_mut())
+    }
+
+    /// Moves the pending operations to the branch of the completed head, passes of blocks
+    /// which are no longer canonical are dropped, those of the new blocks are added,
+    /// does nothing until the operations of the chain are collected
+    fn rebase_operations(&mut self, head: &Hash) -> Result<(), SocketError> {
+        let old = match &self.operations_head {
+            Some(old) if self.pending_operations.is_some() => old.clone(),
+            _ => return Ok(()),
+        };
+        // the whole branch is collected again if the branches do not meet
+        let base = self
+            .chain
+            .common_ancestor(&old, head)
+            .map_or(-1, |(_, level)| level);
+        if let Some(pending) = &mut self.pending_operations {
+            pending.retain(|&(level, _, _), _| level <= base);
+        }
+        let mut current = self.chain.get(head).cloned();
+        while let Some(header) = current.filter(|header| header.level() > base) {
+            let hash = block_hash(&header)?;
+            self.want_operations(&header, &hash);
+            current = self.chain.parent(&header).cloned();
+        }
+        self.operations_head = Some(head.clone());
+        Ok(())
+    }
+
+    /// Adds operations of the block which are not in the store,
+    /// does nothing until the operations of the chain are collected
+    fn want_operations(&mut self, header: &BlockHeader, hash: &Hash) {
+        let pending = match &mut self.pending_operations {
+            Some(pending) => pending,
+            None => return,
+        };
+        for validation_pass in 0..(header.validation_pass() as i8) {
+            let stored = match &self.store {
+                Some(store) => store.contains_operations(hash, validation_pass),
+                None => false,
+            };
+            if !stored {
+                pending.insert((header.level(), hash.clone(), validation_pass), None);
+            }
+        }
+    }
+
+    /// Looks for the protocol change between the header and its neighbors,
+    /// the header must not be inserted yet, so its child is still the lowest in a segment
+    fn discover_protocol(&mut self, header: &BlockHeader, hash: &Hash) {
+        let proto = header.proto();
+        if let Some(parent) = self.chain.parent(header) {
+            if parent.proto() != proto {
+                self.change_protocol(header);
+            }
+        }
+        if let Some(child) = self.chain.child(hash).cloned() {
+            if child.proto() != proto {
+                self.change_protocol(&child);
+            }
+        }
+    }
+
+    /// The header is the first of the new protocol, only the first block after
+    /// the genesis tells the hash, the hashes of the later protocols are configured
+    fn change_protocol(&mut self, header: &BlockHeader) {
+        if let Some(hash) = activated_protocol(header) {
+            self.want_protocol(hash);
+            return;
+        }
+        let proto = header.proto();
+        self.protocol_changes.insert(proto);
+        if let Some(hash) = self.activations.get(&proto).cloned() {
+            self.want_protocol(hash);
+        }
+    }
+
+    fn want_protocol(&mut self, hash: Hash) {
+        let stored = match &self.store {
+            Some(store) => store.contains_protocol(&hash),
+            None => false,
+        };
+        if !stored && !self.protocols.contains(&hash) {
+            self.pending_protocols.entry(hash).or_insert(None);
+        }
+    }
+
+    fn is_operations_complete(&mut self) -> Result<bool, SocketError> {
+        Ok(self
+            .pending_operations()?
+            .map_or(false, |pending| pending.is_empty()))
+    }
+
+    fn checkpoint(&mut self) -> Result<(), SocketError> {
+        let anchors = self
+            .gaps()
+            .into_iter()
+            .filter_map(|(hash, level)| match level {
+                Level::Guess(level) => Some((hash, level)),
+                Level::Precise(_) => None,
+            })
+            .collect();
+        let (store, (head, _)) = match (&mut self.store, &self.head) {
+            (Some(store), Some(head)) => (store, head),
+            _ => return Ok(()),
+        };
+        let checkpoint = Checkpoint {
+            head: head.clone(),
+            anchors: anchors,
+        };
+        store
+            .save_checkpoint(&checkpoint)
+            .map_err(SocketError::Storage)
+    }
+
+    /// Makes the header the canonical head if it is fitter than the current head,
+    /// the header must be in the chain
+    fn update_head(&mut self, hash: Hash, header: &BlockHeader) {
+        let current = match &self.head {
+            Some((current, _)) => current.clone(),
+            None => {
+                self.head = Some((hash, header.level()));
+                return;
+            },
+        };
+        let fitter = match self.chain.get(&current) {
+            Some(current) => fitness::compare(header.fitness(), current.fitness()),
+            None => header.level().cmp(&self.head.as_ref().unwrap().1),
+        };
+        if current == hash || fitter != Ordering::Greater {
+            return;
+        }
+        // hashes from the history of the old head might be on another branch
+        for anchor in self.anchors.drain(..) {
+            self.chain.remove_hash(&anchor);
+        }
+        // the depth is unknown until the new branch is downloaded down to the old one,
+        // so are the operations to drop, none are leased until then
+        let old = match self.pending_reorg.take() {
+            Some((old, _)) => old,
+            None => current,
+        };
+        self.pending_reorg = Some((old, hash.clone()));
+        self.head = Some((hash, header.level()));
+    }
+
+    /// Gaps below the head and its anchors
+    fn gaps(&self) -> Vec<(Hash, Level)> {
+        match &self.head {
+            Some((head, _)) => self.chain.gaps(Some(head).into_iter().chain(&self.anchors)),
+            None => Vec::new(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.head
+            .as_ref()
+            .map_or(false, |(head, _)| self.chain.is_complete(head))
+    }
+
+    /// The branch of the head has no gaps, the head is persisted as the tip,
+    /// does nothing if the completion of the head is already announced
+    fn on_complete(&mut self) -> Result<(), SocketError> {
+        let head = match &self.head {
+            Some((head, _)) => head.clone(),
+            None => return Ok(()),
+        };
+        if self.completed.as_ref() == Some(&head) {
+            return Ok(());
+        }
+        if let Some(store) = &mut self.store {
+            store.set_tip(&head).map_err(SocketError::Storage)?;
+            store.clear_checkpoint().map_err(SocketError::Storage)?;
+        }
+        self.rebase_operations(&head)?;
+        self.completed = Some(head);
+        for anchor in self.anchors.drain(..) {
+            self.chain.remove_hash(&anchor);
+        }
+        if let Some((old_head, new_head)) = self.pending_reorg.take() {
+            let old_level = self.chain.get(&old_head).map(BlockHeader::level);
+            let base = self.chain.common_ancestor(&old_head, &new_head);
+            if let (Some(old_level), Some((_, base_level))) = (old_level, base) {
+                // moving forward along the same branch is not a reorganization
+                let depth = (old_level - base_level) as u32;
+                if depth > 0 {
+                    self.reorgs.push(Reorg {
+                        old_head: old_head,
+                        new_head: new_head,
+                        depth: depth,
+                    });
+                }
+            }
+        }
+        let _ = self.complete.send(true);
+        Ok(())
+    }
+}
@@ -1,24 +1,34 @@
-use std::{mem, convert::TryFrom};
+use std::{mem, convert::TryFrom, net::SocketAddr};
 use slog::Logger;
 use tezos_messages::p2p::{
     encoding::{
         peer::PeerMessageResponse,
-        current_branch::{CurrentBranchMessage, CurrentBranch, GetCurrentBranchMessage},
+        current_branch::{CurrentBranch, GetCurrentBranchMessage},
     },
 };
 use super::{
     SocketError,
+    Config,
     TrustedConnection,
     ChainId,
-    genesis,
-    message::{Request, Response},
+    message::Response,
+    responder::Responder,
     sync_block_headers::SyncBlockHeaders,
+    sync_operations::SyncOperations,
+    sync_protocols::SyncProtocols,
+    follow::Follow,
+    shared_chain::SharedChain,
 };
 
 /// Reference to shared chain state
 pub struct BootstrapState {
     state: FullState,
+    peer: SocketAddr,
+    chain_id: ChainId,
     connection: TrustedConnection<PeerMessageResponse>,
+    config: Config,
+    shared: SharedChain,
+    responder: Responder,
 }
 
 enum FullState {
@@ -29,7 +39,17 @@ enum FullState {
     ReceivedRemoteBranch(SyncBlockHeaders),
     // -> GetBlockHeaders
     // <- BlockHeader
-    // next state is again `ReceivedRemoteBranch` or `FullState::Finish`
+    // next state is `DownloadOperations` once the chain is complete
+    DownloadOperations(SyncOperations),
+    // -> GetOperationsForBlocks
+    // <- OperationsForBlocks
+    FetchProtocols(SyncProtocols),
+    // -> GetProtocols
+    // <- Protocol
+    // next state is `Follow` if configured
+    Follow(Follow),
+    // -> GetCurrentHead
+    // <- CurrentHead
     // TODO: result of bootstrap
     Finish,
     // if peer requested CurrentBranch with unknown chain id
@@ -38,10 +58,27 @@ enum FullState {
 }
 
 impl BootstrapState {
-    pub fn new(connection: TrustedConnection<PeerMessageResponse>, chain_id: ChainId) -> Self {
+    pub fn new(
+        connection: TrustedConnection<PeerMessageResponse>,
+        peer: SocketAddr,
+        chain_id: ChainId,
+        config: &Config,
+        shared: &SharedChain,
+    ) -> Self {
+        // a private peer must never be advertised
+        if !connection.metadata().private_node() {
+            shared.add_public_peer(peer);
+        }
+        let metadata = connection.metadata();
+        let responder = Responder::new(peer, chain_id, shared.clone(), config, metadata);
         BootstrapState {
             state: FullState::Initial(chain_id),
+            peer: peer,
+            chain_id: chain_id,
             connection: connection,
+            config: config.clone(),
+            shared: shared.clone(),
+            responder: responder,
         }
     }
 
@@ -71,7 +108,7 @@ impl BootstrapState {
             },
             FullState::AskedRemoteBranch(chain_id) => {
                 let message = self.connection.read().await?;
-                let to_write = self.handle_peer_request(&message, chain_id, logger);
+                let to_write = self.responder.handle(&message, logger);
                 if !to_write.is_empty() {
                     self.connection.write_batch(to_write.as_ref()).await?;
                 }
@@ -79,13 +116,42 @@ impl BootstrapState {
                     None => FullState::AskedRemoteBranch(chain_id),
                     Some(None) => FullState::UnknownChain,
                     Some(Some(peer_current_branch)) => {
-                        let synchronizer = SyncBlockHeaders::new(peer_current_branch);
+                        let shared = self.shared.clone();
+                        let synchronizer =
+                            SyncBlockHeaders::new(peer_current_branch, shared, &self.config);
                         FullState::ReceivedRemoteBranch(synchronizer)
                     },
                 }
             },
             FullState::ReceivedRemoteBranch(mut s) => {
-                s.run(&mut self.connection, logger).await?;
+                s.run(&mut self.connection, &mut self.responder, logger)
+                    .await?;
+                FullState::DownloadOperations(SyncOperations::new(
+                    self.shared.clone(),
+                    &self.config,
+                ))
+            },
+            FullState::DownloadOperations(mut s) => {
+                s.run(&mut self.connection, &mut self.responder, logger)
+                    .await?;
+                FullState::FetchProtocols(SyncProtocols::new(self.shared.clone(), &self.config))
+            },
+            FullState::FetchProtocols(mut s) => {
+                s.run(&mut self.connection, &mut self.responder, logger)
+                    .await?;
+                match self.config.follow {
+                    Some(interval) => {
+                        let (peer, chain_id) = (self.peer, self.chain_id);
+                        let shared = self.shared.clone();
+                        let follow = Follow::new(peer, chain_id, shared, &self.config, interval);
+                        FullState::Follow(follow)
+                    },
+                    None => FullState::Finish,
+                }
+            },
+            FullState::Follow(mut s) => {
+                s.run(&mut self.connection, &mut self.responder, logger)
+                    .await?;
                 FullState::Finish
             },
             FullState::Finish => FullState::Finish,
@@ -118,27 +184,10 @@ impl BootstrapState {
 
         None
     }
+}
 
-    fn handle_peer_request(
-        &mut self,
-        message: &PeerMessageResponse,
-        chain_id: ChainId,
-        logger: &Logger,
-    ) -> Vec<PeerMessageResponse> {
-        let mut write = Vec::new();
-        for request in Request::filter(message) {
-            match request {
-                Request::GetCurrentBranch(m) => {
-                    if ChainId::try_from(m.chain_id.clone()).unwrap() == chain_id {
-                        let genesis_block_header = genesis::block_header();
-                        let current_branch = CurrentBranch::new(genesis_block_header, Vec::new());
-                        let response = CurrentBranchMessage::new(chain_id.to_vec(), current_branch);
-                        write.push(response.into())
-                    }
-                },
-                r => slog::warn!(logger, "ignored message {:x?}", r),
-            }
-        }
-        write
+impl Drop for BootstrapState {
+    fn drop(&mut self) {
+        self.shared.remove_public_peer(&self.peer);
     }
 }
@@ -1,4 +1,8 @@
-use std::io::Write;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 use slog::Logger;
 use serde::{Serialize, Deserialize};
 use tezos_messages::{
@@ -13,55 +17,164 @@ use tezos_messages::{
     },
 };
 use tezos_encoding::{has_encoding, encoding::{HasEncoding, Encoding, Field}};
-use super::{SocketError, TrustedConnection};
+use crypto::hash::Hash;
+use super::{
+    SocketError,
+    SyncError,
+    Config,
+    TrustedConnection,
+    block_hash,
+    message::MAX_REQUEST_LENGTH,
+    responder::Responder,
+    shared_chain::SharedChain,
+};
 
 pub struct SyncBlockHeaders {
     remote_branch: CurrentBranch,
+    shared: SharedChain,
+    peer: usize,
+    /// requested headers with their deadlines
+    in_flight: HashMap<Hash, Instant>,
+    window: usize,
+    timeout: Duration,
+    export: Option<PathBuf>,
 }
 
 impl SyncBlockHeaders {
-    pub fn new(remote_branch: CurrentBranch) -> Self {
+    pub fn new(remote_branch: CurrentBranch, shared: SharedChain, config: &Config) -> Self {
         SyncBlockHeaders {
             remote_branch: remote_branch,
+            peer: shared.register(),
+            shared: shared,
+            in_flight: HashMap::new(),
+            window: config.window,
+            timeout: config.request_timeout,
+            export: config.export.clone(),
         }
     }
 
     pub async fn run(
         &mut self,
         connection: &mut TrustedConnection<PeerMessageResponse>,
-        _logger: &Logger,
+        responder: &mut Responder,
+        logger: &Logger,
     ) -> Result<(), SocketError> {
-        // TODO:
-        // let head: &BlockHeader = self.remote_branch.current_head();
+        // start from the head, the history goes from the head to the genesis,
+        // each entry is at least one level lower, so the guess is an upper bound
+        let head = self.remote_branch.current_head();
+        let head_level = head.level() as u32;
+        let anchors = self
+            .remote_branch
+            .history()
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| (hash.clone(), head_level.saturating_sub(i as u32 + 1)));
+        self.shared.accept_head(head, anchors)?;
+
+        while !self.shared.is_complete() {
+            let available = self.window.saturating_sub(self.in_flight.len());
+            let request = self.shared.claim(self.peer, available, self.timeout);
+            if !request.is_empty() {
+                let deadline = Instant::now() + self.timeout;
+                self.in_flight
+                    .extend(request.iter().map(|hash| (hash.clone(), deadline)));
+                let messages = request
+                    .chunks(MAX_REQUEST_LENGTH)
+                    .map(|hashes| GetBlockHeadersMessage::new(hashes.to_vec()).into())
+                    .collect::<Vec<_>>();
+                connection.write_batch(&messages).await?;
+            }
 
-        let len = self.remote_branch.history().len();
-        let mut last = self.remote_branch.history()[len - 2].clone();
-        let mut chain = Chain {
-            headers: Vec::<BlockHeader>::new(),
-            body: Default::default(),
-        };
-        loop {
-            connection.write(&GetBlockHeadersMessage::new(vec![last.clone()]).into()).await?;
-            let r = connection.read().await?;
-            match &r.messages()[0] {
-                &PeerMessage::BlockHeader(ref h) => {
-                    if h.block_header().predecessor().eq(&last) {
-                        continue;
+            let r = match self.in_flight.values().min().cloned() {
+                // other peers hold every gap, wait until they finish or their leases expire
+                None => tokio::select! {
+                    _ = self.shared.completed() => break,
+                    _ = tokio::time::sleep(self.timeout) => continue,
+                },
+                Some(deadline) => {
+                    // the peer does not answer, its leases go to other peers once it is dropped
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(SocketError::Sync(SyncError::Timeout));
                     }
-                    chain.headers.push(h.block_header().clone());
-                    if h.block_header().level() == 0 {
-                        break;
+                    tokio::select! {
+                        _ = self.shared.completed() => break,
+                        r = connection.read() => r?,
+                        _ = tokio::time::sleep(deadline - now) => continue,
                     }
-                    last = h.block_header().predecessor().clone();
                 },
-                _ => (),
+            };
+            for message in r.messages() {
+                match message {
+                    &PeerMessage::BlockHeader(ref h) => self.accept(h.block_header())?,
+                    _ => (),
+                }
+            }
+            let to_write = responder.handle(&r, logger);
+            if !to_write.is_empty() {
+                connection.write_batch(to_write.as_ref()).await?;
             }
         }
-        let data = chain.as_bytes().unwrap();
-        let mut file = std::fs::File::create("target/data.dump").unwrap();
-        file.write_all(data.as_ref()).unwrap();
+
+        if let Some(path) = &self.export {
+            if let Some(count) = self.shared.export(path)? {
+                slog::info!(logger, "exported {} block headers", count);
+            }
+        }
+        for reorg in self.shared.take_reorgs() {
+            slog::warn!(
+                logger,
+                "reorganization from {:x?} to {:x?}, depth {}",
+                reorg.old_head,
+                reorg.new_head,
+                reorg.depth,
+            );
+        }
         Ok(())
     }
+
+    fn accept(&mut self, header: &BlockHeader) -> Result<(), SocketError> {
+        let hash = block_hash(header)?;
+        if self.in_flight.remove(&hash).is_none() {
+            if self.shared.contains(&hash) {
+                // duplicate of the header received before
+                return Ok(());
+            }
+            let error = SyncError::UnrequestedHeader { received: hash };
+            return Err(SocketError::Sync(error));
+        }
+        self.shared.accept(header)
+    }
+}
+
+impl Drop for SyncBlockHeaders {
+    fn drop(&mut self) {
+        // let other peers download what this peer did not
+        self.shared.release(self.peer, self.in_flight.keys());
+    }
+}
+
+/// Reads headers written by the previous run, from the head to the genesis
+pub fn read_dump<P>(path: P) -> Result<Vec<BlockHeader>, SocketError>
+where
+    P: AsRef<Path>,
+{
+    let data = std::fs::read(path).map_err(SocketError::Io)?;
+    let chain = Chain::from_bytes(data).map_err(|_| SocketError::DecodingError)?;
+    Ok(chain.headers)
+}
+
+/// Writes headers in the encoding of `read_dump`, from the head to the genesis
+pub fn write_dump<P>(path: P, headers: Vec<BlockHeader>) -> Result<(), SocketError>
+where
+    P: AsRef<Path>,
+{
+    let chain = Chain {
+        headers: headers,
+        body: Default::default(),
+    };
+    let data = chain.as_bytes().map_err(|_| SocketError::EncodingError)?;
+    std::fs::write(path, data).map_err(SocketError::Io)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use slog::Logger;
+use tezos_messages::p2p::encoding::{
+    peer::{PeerMessageResponse, PeerMessage},
+    operations_for_blocks::{
+        OperationsForBlock, OperationsForBlocksMessage, GetOperationsForBlocksMessage,
+    },
+};
+use crypto::hash::Hash;
+use super::{
+    SocketError,
+    SyncError,
+    Config,
+    TrustedConnection,
+    message::MAX_REQUEST_LENGTH,
+    responder::Responder,
+    shared_chain::SharedChain,
+};
+
+/// Downloads operations of the blocks of the complete chain, pass by pass
+pub struct SyncOperations {
+    shared: SharedChain,
+    peer: usize,
+    /// requested passes with their deadlines
+    in_flight: HashMap<(Hash, i8), Instant>,
+    window: usize,
+    timeout: Duration,
+}
+
+impl SyncOperations {
+    pub fn new(shared: SharedChain, config: &Config) -> Self {
+        SyncOperations {
+            peer: shared.register(),
+            shared: shared,
+            in_flight: HashMap::new(),
+            window: config.window,
+            timeout: config.request_timeout,
+        }
+    }
+
+    pub async fn run(
+        &mut self,
+        connection: &mut TrustedConnection<PeerMessageResponse>,
+        responder: &mut Responder,
+        logger: &Logger,
+    ) -> Result<(), SocketError> {
+        while !self.shared.is_operations_complete()? {
+            let available = self.window.saturating_sub(self.in_flight.len());
+            let request = self
+                .shared
+                .claim_operations(self.peer, available, self.timeout)?;
+            if !request.is_empty() {
+                let blocks = request
+                    .iter()
+                    .map(|(hash, validation_pass)| {
+                        OperationsForBlock::new(hash.clone(), *validation_pass)
+                    })
+                    .collect::<Vec<_>>();
+                let deadline = Instant::now() + self.timeout;
+                self.in_flight
+                    .extend(request.into_iter().map(|key| (key, deadline)));
+                let messages = blocks
+                    .chunks(MAX_REQUEST_LENGTH)
+                    .map(|blocks| GetOperationsForBlocksMessage::new(blocks.to_vec()).into())
+                    .collect::<Vec<_>>();
+                connection.write_batch(&messages).await?;
+            }
+
+            let r = match self.in_flight.values().min().cloned() {
+                // other peers hold every pass, wait until they finish or their leases expire
+                None => tokio::select! {
+                    _ = self.shared.operations_completed() => break,
+                    _ = tokio::time::sleep(self.timeout) => continue,
+                },
+                Some(deadline) => {
+                    // the peer does not answer, for example it has pruned old operations,
+                    // its leases go to other peers once it is dropped
+                    let now = Instant::now();
+                    if deadline <= now {
+                        return Err(SocketError::Sync(SyncError::Timeout));
+                    }
+                    tokio::select! {
+                        _ = self.shared.operations_completed() => break,
+                        r = connection.read() => r?,
+                        _ = tokio::time::sleep(deadline - now) => continue,
+                    }
+                },
+            };
+            for message in r.messages() {
+                match message {
+                    &PeerMessage::OperationsForBlocks(ref m) => self.accept(m)?,
+                    &PeerMessage::OperationHashesForBlock(ref m) => {
+                        self.shared.check_operation_hashes(m)?
+                    },
+                    _ => (),
+                }
+            }
+            let to_write = responder.handle(&r, logger);
+            if !to_write.is_empty() {
+                connection.write_batch(to_write.as_ref()).await?;
+            }
+        }
+        slog::info!(logger, "downloaded operations");
+        Ok(())
+    }
+
+    fn accept(&mut self, operations: &OperationsForBlocksMessage) -> Result<(), SocketError> {
+        let block = operations.operations_for_block();
+        let key = (block.hash().clone(), *block.validation_pass());
+        if self.in_flight.remove(&key).is_none() {
+            let error = SyncError::UnrequestedOperations {
+                received: key.0,
+                validation_pass: key.1,
+            };
+            return Err(SocketError::Sync(error));
+        }
+        self.shared.accept_operations(operations)
+    }
+}
+
+impl Drop for SyncOperations {
+    fn drop(&mut self) {
+        // let other peers download what this peer did not
+        self.shared.release_operations(self.peer, self.in_flight.keys());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+use slog::Logger;
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        block_header::BlockHeader,
+        peer::{PeerMessageResponse, PeerMessage},
+        protocol::{Protocol, GetProtocolsMessage},
+    },
+};
+use crypto::{blake2b, hash::Hash};
+use super::{
+    SocketError,
+    SyncError,
+    Config,
+    TrustedConnection,
+    message::MAX_REQUEST_LENGTH,
+    responder::Responder,
+    shared_chain::SharedChain,
+};
+
+/// Tag of the `Activate` command in the protocol data of the genesis protocol
+const ACTIVATE: u8 = 0;
+
+/// The protocol activated by the block, the first block after the genesis activates
+/// the protocol whose hash follows the command tag in the protocol data,
+/// later blocks only increment `proto`, the hash of their protocol is not in the header
+pub fn activated_protocol(header: &BlockHeader) -> Option<Hash> {
+    let data = header.protocol_data();
+    match (header.level(), data.first()) {
+        (1, Some(&ACTIVATE)) => data.get(1..33).map(<[u8]>::to_vec),
+        _ => None,
+    }
+}
+
+/// The hash of the protocol, computed over the encoded components
+pub fn protocol_hash(protocol: &Protocol) -> Result<Hash, SocketError> {
+    protocol
+        .as_bytes()
+        .map(|bytes| blake2b::digest_256(bytes.as_ref()))
+        .map_err(|_| SocketError::EncodingError)
+}
+
+/// Downloads sources of the protocols used by the chain
+pub struct SyncProtocols {
+    shared: SharedChain,
+    peer: usize,
+    /// requested protocols with their deadlines
+    in_flight: HashMap<Hash, Instant>,
+    /// protocols the peer did not send in time, they are left to other peers
+    missing: HashSet<Hash>,
+    timeout: Duration,
+}
+
+impl SyncProtocols {
+    pub fn new(shared: SharedChain, config: &Config) -> Self {
+        shared.want_protocols(config.protocols.iter().cloned());
+        shared.want_activations(config.activations.iter().cloned());
+        SyncProtocols {
+            peer: shared.register(),
+            shared: shared,
+            in_flight: HashMap::new(),
+            missing: HashSet::new(),
+            timeout: config.request_timeout,
+        }
+    }
+
+    pub async fn run(
+        &mut self,
+        connection: &mut TrustedConnection<PeerMessageResponse>,
+        responder: &mut Responder,
+        logger: &Logger,
+    ) -> Result<(), SocketError> {
+        for proto in self.shared.unknown_activations() {
+            slog::warn!(logger, "the protocol of proto level {} is not configured", proto);
+        }
+        while !self.shared.is_protocols_complete() {
+            let (missing, request) = self
+                .shared
+                .claim_protocols(self.peer, self.timeout)
+                .into_iter()
+                .partition::<Vec<_>, _>(|hash| self.missing.contains(hash));
+            self.shared.release_protocols(self.peer, missing.iter());
+            if !request.is_empty() {
+                let deadline = Instant::now() + self.timeout;
+                self.in_flight
+                    .extend(request.iter().map(|hash| (hash.clone(), deadline)));
+                let messages = request
+                    .chunks(MAX_REQUEST_LENGTH)
+                    .map(|hashes| GetProtocolsMessage::new(hashes.to_vec()).into())
+                    .collect::<Vec<_>>();
+                connection.write_batch(&messages).await?;
+            }
+
+            let r = match self.in_flight.values().min().cloned() {
+                // the peer cannot help with the rest, it goes on to follow the chain
+                None if !self.missing.is_empty() => {
+                    slog::warn!(logger, "the peer does not have {} protocols", self.missing.len());
+                    break;
+                },
+                // other peers fetch the rest
+                None => {
+                    tokio::time::sleep(self.timeout).await;
+                    continue;
+                },
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        // the peer does not have the protocols, other peers might have them
+                        let expired = self
+                            .in_flight
+                            .iter()
+                            .filter(|&(_, deadline)| *deadline <= now)
+                            .map(|(hash, _)| hash.clone())
+                            .collect::<Vec<_>>();
+                        for hash in &expired {
+                            self.in_flight.remove(hash);
+                        }
+                        self.shared.release_protocols(self.peer, expired.iter());
+                        self.missing.extend(expired);
+                        continue;
+                    }
+                    tokio::select! {
+                        r = connection.read() => r?,
+                        _ = tokio::time::sleep(deadline - now) => continue,
+                    }
+                },
+            };
+            for message in r.messages() {
+                match message {
+                    &PeerMessage::Protocol(ref m) => self.accept(m.protocol())?,
+                    _ => (),
+                }
+            }
+            let to_write = responder.handle(&r, logger);
+            if !to_write.is_empty() {
+                connection.write_batch(to_write.as_ref()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, protocol: &Protocol) -> Result<(), SocketError> {
+        let hash = protocol_hash(protocol)?;
+        // the protocol which comes too late is still good
+        if self.in_flight.remove(&hash).is_none() && !self.missing.remove(&hash) {
+            let error = SyncError::UnrequestedProtocol { received: hash };
+            return Err(SocketError::Sync(error));
+        }
+        self.shared.accept_protocol(&hash, protocol)
+    }
+}
+
+impl Drop for SyncProtocols {
+    fn drop(&mut self) {
+        self.shared.release_protocols(self.peer, self.in_flight.keys());
+    }
+}
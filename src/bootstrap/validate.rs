@@ -0,0 +1,155 @@
+use std::{
+    cmp::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tezos_messages::p2p::{binary_message::BinaryMessage, encoding::block_header::BlockHeader};
+use super::{SocketError, SyncError, genesis, fitness};
+
+/// How far in the future a block timestamp might be, in seconds
+pub const FUTURE_TOLERANCE: i64 = 15;
+
+/// Checks the header against the header which follows it
+pub fn link(header: &BlockHeader, predecessor: &BlockHeader) -> Result<(), SyncError> {
+    let level = header.level();
+    if level != predecessor.level() + 1 {
+        return Err(SyncError::Level {
+            level: level,
+            predecessor: predecessor.level(),
+        });
+    }
+    if header.timestamp() <= predecessor.timestamp() {
+        return Err(SyncError::Timestamp { level: level });
+    }
+    if fitness::compare(header.fitness(), predecessor.fitness()) == Ordering::Less {
+        return Err(SyncError::Fitness { level: level });
+    }
+    Ok(())
+}
+
+pub fn timestamp(header: &BlockHeader) -> Result<(), SyncError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    timestamp_at(header, now)
+}
+
+fn timestamp_at(header: &BlockHeader, now: i64) -> Result<(), SyncError> {
+    if header.timestamp() > now + FUTURE_TOLERANCE {
+        Err(SyncError::FutureTimestamp {
+            level: header.level(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// The header of level zero must be the genesis of the network
+pub fn genesis(header: &BlockHeader) -> Result<(), SocketError> {
+    let encode = |header: &BlockHeader| header.as_bytes().map_err(|_| SocketError::EncodingError);
+    if encode(header)? != encode(&genesis::block_header())? {
+        Err(SocketError::Sync(SyncError::Genesis))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use tezos_messages::p2p::encoding::block_header::{BlockHeader, BlockHeaderBuilder};
+    use super::{SyncError, SocketError, FUTURE_TOLERANCE, fitness, genesis, link, timestamp_at};
+    use crate::testing::{self, ChainGenerator};
+
+    fn at(timestamp: i64) -> BlockHeader {
+        BlockHeaderBuilder::default()
+            .level(1)
+            .proto(1)
+            .predecessor(vec![0; 32])
+            .timestamp(timestamp)
+            .validation_pass(0)
+            .operations_hash(vec![0; 32])
+            .fitness(vec![])
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn level() {
+        let headers = testing::chain(4);
+        assert!(link(&headers[2], &headers[1]).is_ok());
+        match link(&headers[3], &headers[1]) {
+            Err(SyncError::Level { level: 3, predecessor: 1 }) => (),
+            _ => panic!("the level must follow the predecessor"),
+        }
+        assert!(link(&headers[1], &headers[1]).is_err());
+    }
+
+    #[test]
+    fn timestamp() {
+        for &interval in &[0, -1] {
+            let headers = ChainGenerator::new(3).interval(interval).generate();
+            match link(&headers[2], &headers[1]) {
+                Err(SyncError::Timestamp { level: 2 }) => (),
+                _ => panic!("the timestamp must be after the predecessor"),
+            }
+        }
+    }
+
+    #[test]
+    fn future_tolerance() {
+        let now = 1_600_000_000;
+        assert!(timestamp_at(&at(now + FUTURE_TOLERANCE), now).is_ok());
+        match timestamp_at(&at(now + FUTURE_TOLERANCE + 1), now) {
+            Err(SyncError::FutureTimestamp { level: 1 }) => (),
+            _ => panic!("the timestamp is too far in the future"),
+        }
+    }
+
+    #[test]
+    fn fitness() {
+        // the longer list is fitter whatever the elements are
+        let short = vec![vec![0xff; 8]];
+        let long = vec![vec![0], vec![0]];
+        assert_eq!(fitness::compare(&long, &short), Ordering::Greater);
+        // the longer element is fitter
+        assert_eq!(fitness::compare(&vec![vec![1, 0]], &vec![vec![2]]), Ordering::Greater);
+        // elements of the same length are compared as bytes
+        let (a, b) = (vec![vec![1], vec![0, 1]], vec![vec![1], vec![0, 2]]);
+        assert_eq!(fitness::compare(&a, &b), Ordering::Less);
+        assert_eq!(fitness::compare(&a, &a), Ordering::Equal);
+        // lower fitness than the predecessor
+        let headers = ChainGenerator::new(3)
+            .fitness(|level| vec![vec![10 - level as u8]])
+            .generate();
+        match link(&headers[2], &headers[1]) {
+            Err(SyncError::Fitness { level: 2 }) => (),
+            _ => panic!("the fitness must not decrease"),
+        }
+    }
+
+    #[test]
+    fn genesis_mismatch() {
+        assert!(super::genesis(&genesis::block_header()).is_ok());
+        let expected = genesis::block_header();
+        // the same genesis with another context
+        let other = BlockHeaderBuilder::default()
+            .level(0)
+            .proto(0)
+            .predecessor(expected.predecessor().clone())
+            .timestamp(expected.timestamp())
+            .validation_pass(0)
+            .operations_hash(expected.operations_hash().clone())
+            .fitness(vec![])
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap();
+        match super::genesis(&other) {
+            Err(SocketError::Sync(SyncError::Genesis)) => (),
+            _ => panic!("the genesis must be rejected"),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{path::PathBuf, time::Duration};
+use crypto::hash::Hash;
+
+/// Parameters of the bootstrap
+#[derive(Clone)]
+pub struct Config {
+    /// how many block headers might be requested from a peer and not yet received
+    pub window: usize,
+    /// if a peer does not answer in time, its requests are given to other peers
+    pub request_timeout: Duration,
+    /// block headers per second served to a peer
+    pub serve_rate: u32,
+    /// block headers which might be served to a peer at once
+    pub serve_burst: u32,
+    /// protocols to download besides those found in the block headers
+    pub protocols: Vec<Hash>,
+    /// hashes of the protocols by the `proto` level of the block headers,
+    /// the header tells only that the protocol changes, not which protocol comes
+    pub activations: Vec<(u8, Hash)>,
+    /// keep following the head of the peer after the bootstrap, asking for it so often
+    pub follow: Option<Duration>,
+    /// ask peers not to send their mempool, it is never asked for without a mempool observer
+    pub disable_mempool: bool,
+    /// ask peers not to advertise this node
+    pub private_node: bool,
+    /// write the chain to this file once it is complete, nothing is written without the path
+    pub export: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            window: 64,
+            request_timeout: Duration::from_secs(30),
+            serve_rate: 100,
+            serve_burst: 500,
+            protocols: Vec::new(),
+            activations: Vec::new(),
+            follow: None,
+            disable_mempool: false,
+            private_node: false,
+            export: None,
+        }
+    }
+}
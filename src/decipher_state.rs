@@ -57,13 +57,13 @@ impl DecipherState {
                 &mut self.initiators_counter,
             )
         };
-        self.decipher
+        let plain = self
+            .decipher
             .decrypt(data, chunk_number)
-            .map_err(SocketError::Decryption)
-            .map(|c| {
-                *counter += 1;
-                c
-            })
+            .map_err(SocketError::Decryption);
+        // the sender spent the nonce on this chunk even if it is corrupted
+        *counter += 1;
+        plain
     }
 
     pub async fn write_message<T, M>(
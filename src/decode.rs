@@ -0,0 +1,320 @@
+use std::{fs, path::Path};
+use serde::Serialize;
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        connection::ConnectionMessage,
+        metadata::MetadataMessage,
+        ack::AckMessage,
+        peer::{PeerMessage, PeerMessageResponse},
+    },
+};
+use tezos_encoding::binary_reader::BinaryReaderError;
+use tezos_conversation::Identity;
+use super::{
+    error::DecodeError,
+    decipher_state::DecipherState,
+    pcap::{self, Stream},
+};
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Initiator,
+    Responder,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message {
+    Connection(ConnectionMessage),
+    Metadata(MetadataMessage),
+    Ack(AckMessage),
+    Peer(PeerMessage),
+}
+
+/// Decoded message or decoding error found in the conversation
+#[derive(Serialize)]
+pub struct Event {
+    /// the side which sent it
+    pub direction: Direction,
+    /// capture time in microseconds, unknown when decoding raw streams,
+    /// then events of both sides alternate by the chunk index
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// index of the first and the last chunk
+    pub chunks: (usize, usize),
+    /// offset of the first byte and the end offset in the stream
+    pub bytes: (usize, usize),
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Event {
+    fn new(
+        direction: Direction,
+        stream: &Stream,
+        chunks: (usize, usize),
+        bytes: (usize, usize),
+        message: Result<Message, String>,
+    ) -> Self {
+        let (message, error) = match message {
+            Ok(message) => (Some(message), None),
+            Err(error) => (None, Some(error)),
+        };
+        Event {
+            direction: direction,
+            time: stream.time(bytes.1),
+            chunks: chunks,
+            bytes: bytes,
+            message: message,
+            error: error,
+        }
+    }
+}
+
+/// Decodes the first tcp conversation of the libpcap file
+pub fn decode_pcap<P, Q>(path: P, identity: Q) -> Result<Vec<Event>, DecodeError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let flow = pcap::read_flow(path)?;
+    decode(identity.as_ref(), flow.initiator, flow.responder)
+}
+
+/// Decodes the conversation captured as two raw streams, one file per direction
+pub fn decode_streams<P, Q, R>(
+    initiator: P,
+    responder: Q,
+    identity: R,
+) -> Result<Vec<Event>, DecodeError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let initiator = fs::read(initiator).map_err(DecodeError::Io)?;
+    let responder = fs::read(responder).map_err(DecodeError::Io)?;
+    decode(identity.as_ref(), Stream::new(initiator), Stream::new(responder))
+}
+
+fn decode(
+    identity: &Path,
+    initiator: Stream,
+    responder: Stream,
+) -> Result<Vec<Event>, DecodeError> {
+    let identity = Identity::from_path(identity.display().to_string())
+        .map_err(|_| DecodeError::Identity)?;
+
+    let first_chunk = |data: &[u8]| split(data).0.first().cloned();
+    let (initiator_chunk, responder_chunk) =
+        match (first_chunk(&initiator.data), first_chunk(&responder.data)) {
+            (Some((i_start, i_end)), Some((r_start, r_end))) => (
+                &initiator.data[i_start..i_end],
+                &responder.data[r_start..r_end],
+            ),
+            _ => return Err(DecodeError::NoConnectionMessage),
+        };
+    let decipher = |initiator| {
+        identity
+            .decipher(initiator_chunk, responder_chunk)
+            .map(|d| DecipherState::new(d, initiator))
+            .map_err(|_| DecodeError::Identity)
+    };
+    // the state decrypts chunks of the remote side, so the responder's state reads the initiator
+    let mut events = decode_stream(Direction::Initiator, &initiator, decipher(false)?);
+    events.extend(decode_stream(Direction::Responder, &responder, decipher(true)?));
+    sort(&mut events);
+    Ok(events)
+}
+
+/// Orders events by the capture time, then by the chunk index, the initiator goes first,
+/// so raw streams without time follow the handshake, where the sides take turns
+fn sort(events: &mut [Event]) {
+    // the sort is stable, events of the initiator are before those of the responder
+    events.sort_by_key(|e| (e.time, e.chunks.0));
+}
+
+/// Ranges of complete chunks including the size prefix, and the offset of incomplete tail
+fn split(data: &[u8]) -> (Vec<(usize, usize)>, Option<usize>) {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = match data.get(offset..(offset + 2)) {
+            Some(size) => offset + 2 + (u16::from_be_bytes([size[0], size[1]]) as usize),
+            None => break,
+        };
+        if end > data.len() {
+            break;
+        }
+        chunks.push((offset, end));
+        offset = end;
+    }
+    let tail = if offset < data.len() { Some(offset) } else { None };
+    (chunks, tail)
+}
+
+fn decode_stream(direction: Direction, stream: &Stream, mut decipher: DecipherState) -> Vec<Event> {
+    let (chunks, tail) = split(&stream.data);
+    let mut events = Vec::new();
+    let mut buffer = Vec::new();
+    // index of the first chunk and the offset of the first byte of the buffered message
+    let mut buffered = None::<(usize, usize)>;
+    for (index, &(start, end)) in chunks.iter().enumerate() {
+        let content = &stream.data[(start + 2)..end];
+        let event = |first: (usize, usize), message| {
+            Event::new(direction, stream, (first.0, index), (first.1, end), message)
+        };
+        if index == 0 {
+            let message = ConnectionMessage::from_bytes(content)
+                .map(Message::Connection)
+                .map_err(|e| format!("{:?}", e));
+            events.push(event((index, start), message));
+            continue;
+        }
+        let plain = match decipher.decrypt(content) {
+            Ok(plain) => plain,
+            Err(e) => {
+                // the message in the buffer lost this chunk, start over with the next one
+                let first = buffered.take().unwrap_or((index, start));
+                buffer.clear();
+                events.push(event(first, Err(e.to_string())));
+                continue;
+            },
+        };
+        match index {
+            1 => {
+                let message = MetadataMessage::from_bytes(plain)
+                    .map(Message::Metadata)
+                    .map_err(|e| format!("{:?}", e));
+                events.push(event((index, start), message));
+            },
+            2 => {
+                let message = AckMessage::from_bytes(plain)
+                    .map(Message::Ack)
+                    .map_err(|e| format!("{:?}", e));
+                events.push(event((index, start), message));
+            },
+            _ => {
+                let first = *buffered.get_or_insert((index, start));
+                buffer.extend_from_slice(plain.as_ref());
+                match PeerMessageResponse::from_bytes(&buffer) {
+                    Ok(response) => {
+                        for message in response.messages() {
+                            events.push(event(first, Ok(Message::Peer(message.clone()))));
+                        }
+                    },
+                    Err(BinaryReaderError::Underflow { .. }) => continue,
+                    Err(e) => events.push(event(first, Err(format!("{:?}", e)))),
+                }
+                buffer.clear();
+                buffered = None;
+            },
+        }
+    }
+    if let Some((index, start)) = buffered {
+        let end = chunks.last().map(|&(_, end)| end).unwrap_or(start);
+        let chunks = (index, chunks.len() - 1);
+        let message = Err("incomplete message".to_string());
+        events.push(Event::new(direction, stream, chunks, (start, end), message));
+    }
+    if let Some(start) = tail {
+        let chunks = (chunks.len(), chunks.len());
+        let message = Err("incomplete chunk".to_string());
+        let bytes = (start, stream.data.len());
+        events.push(Event::new(direction, stream, chunks, bytes, message));
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_messages::p2p::{
+        binary_message::BinaryMessage,
+        encoding::{block_header::GetBlockHeadersMessage, peer::PeerMessageResponse},
+    };
+    use tezos_conversation::Identity;
+    use super::{Direction, Event, decode_stream, sort, split};
+    use crate::{decipher_state::DecipherState, handshake_state::connection_chunk, pcap::Stream};
+
+    #[test]
+    fn incomplete_tail() {
+        let data = [0, 2, 0xaa, 0xbb, 0, 1, 0xcc, 0, 3, 0xdd];
+        assert_eq!(split(&data), (vec![(0, 4), (4, 7)], Some(7)));
+        assert_eq!(split(&data[..8]), (vec![(0, 4), (4, 7)], Some(7)));
+        assert_eq!(split(&data[..7]), (vec![(0, 4), (4, 7)], None));
+    }
+
+    #[test]
+    fn raw_streams_alternate() {
+        let stream = Stream::new(Vec::new());
+        let event = |direction, index| {
+            let message = Err("message".to_string());
+            Event::new(direction, &stream, (index, index), (0, 0), message)
+        };
+        let mut events = (0..3)
+            .map(|index| event(Direction::Initiator, index))
+            .chain((0..3).map(|index| event(Direction::Responder, index)))
+            .collect::<Vec<_>>();
+        sort(&mut events);
+        let order = events
+            .iter()
+            .map(|e| (matches!(e.direction, Direction::Initiator), e.chunks.0))
+            .collect::<Vec<_>>();
+        let expected = vec![(true, 0), (false, 0), (true, 1), (false, 1), (true, 2), (false, 2)];
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn captured_time_goes_first() {
+        let mut initiator = Stream::new(vec![0; 8]);
+        initiator.marks = vec![(4, 10), (8, 30)];
+        let mut responder = Stream::new(vec![0; 4]);
+        responder.marks = vec![(4, 20)];
+        let mut events = vec![
+            Event::new(Direction::Initiator, &initiator, (0, 0), (0, 4), Err(String::new())),
+            Event::new(Direction::Initiator, &initiator, (1, 1), (4, 8), Err(String::new())),
+            Event::new(Direction::Responder, &responder, (0, 0), (0, 4), Err(String::new())),
+        ];
+        sort(&mut events);
+        let times = events.iter().map(|e| e.time).collect::<Vec<_>>();
+        assert_eq!(times, vec![Some(10), Some(20), Some(30)]);
+    }
+
+    #[test]
+    fn corrupted_chunk() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testing/identity.json");
+        let identity = Identity::from_path(path.to_string()).unwrap();
+        let connection = connection_chunk(&identity).unwrap();
+        let decipher = |initiator| {
+            let decipher = identity
+                .decipher(connection.raw(), connection.raw())
+                .ok()
+                .unwrap();
+            DecipherState::new(decipher, initiator)
+        };
+        let response: PeerMessageResponse = GetBlockHeadersMessage::new(Vec::new()).into();
+        let message = response.as_bytes().unwrap();
+
+        // the metadata, the ack, then the corrupted chunk and the intact one
+        let mut sender = decipher(true);
+        let mut data = connection.raw().to_vec();
+        for index in 1..5 {
+            let mut chunk = sender.encrypt(&message).unwrap().raw().to_vec();
+            if index == 3 {
+                let last = chunk.len() - 1;
+                chunk[last] ^= 0xff;
+            }
+            data.extend_from_slice(&chunk);
+        }
+        let events = decode_stream(Direction::Initiator, &Stream::new(data), decipher(false));
+        let decoded = events[3..]
+            .iter()
+            .map(|e| (e.chunks, e.message.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(decoded, vec![((3, 3), false), ((4, 4), true)]);
+    }
+}
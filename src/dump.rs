@@ -0,0 +1,147 @@
+//! Inspection and export of the chain dump
+
+use std::{fmt, io};
+use serde::Serialize;
+use tezos_messages::p2p::encoding::block_header::BlockHeader;
+use super::{error::SocketError, bootstrap::block_hash};
+
+/// Overview of the headers in the dump, in the order of the file
+pub struct Summary {
+    pub count: usize,
+    /// the lowest and the highest level
+    pub levels: Option<(i32, i32)>,
+    pub first: Option<String>,
+    pub last: Option<String>,
+    /// levels of the headers which do not link to the neighbor below them
+    pub breaks: Vec<i32>,
+}
+
+impl Summary {
+    pub fn new(headers: &[BlockHeader]) -> Result<Self, SocketError> {
+        let hashes = headers
+            .iter()
+            .map(block_hash)
+            .collect::<Result<Vec<_>, _>>()?;
+        let breaks = (1..headers.len())
+            .filter_map(|i| {
+                // the dump goes either from the head or from the genesis
+                let (upper, lower) = if headers[i - 1].level() > headers[i].level() {
+                    (i - 1, i)
+                } else {
+                    (i, i - 1)
+                };
+                let level = headers[upper].level();
+                let linked = level == headers[lower].level() + 1
+                    && headers[upper].predecessor() == &hashes[lower];
+                if linked {
+                    None
+                } else {
+                    Some(level)
+                }
+            })
+            .collect();
+        let lowest = headers.iter().map(BlockHeader::level).min();
+        let highest = headers.iter().map(BlockHeader::level).max();
+        Ok(Summary {
+            count: headers.len(),
+            levels: lowest.and_then(|lowest| highest.map(|highest| (lowest, highest))),
+            first: hashes.first().map(hex::encode),
+            last: hashes.last().map(hex::encode),
+            breaks: breaks,
+        })
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.breaks.is_empty()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "headers: {}", self.count)?;
+        if let Some((lowest, highest)) = self.levels {
+            writeln!(f, "levels: {}..={}", lowest, highest)?;
+        }
+        if let (Some(first), Some(last)) = (&self.first, &self.last) {
+            writeln!(f, "first: {}", first)?;
+            writeln!(f, "last: {}", last)?;
+        }
+        if self.is_linked() {
+            write!(f, "linkage: ok")
+        } else {
+            write!(f, "linkage: broken at levels {:?}", self.breaks)
+        }
+    }
+}
+
+/// The header in the exported form, hashes and binary fields are hex encoded
+#[derive(Serialize)]
+pub struct Row {
+    pub level: i32,
+    pub hash: String,
+    pub predecessor: String,
+    pub timestamp: i64,
+    pub proto: u8,
+    pub validation_pass: u8,
+    pub operations_hash: String,
+    pub fitness: Vec<String>,
+    pub context: String,
+}
+
+impl Row {
+    pub fn new(header: &BlockHeader) -> Result<Self, SocketError> {
+        Ok(Row {
+            level: header.level(),
+            hash: hex::encode(block_hash(header)?),
+            predecessor: hex::encode(header.predecessor()),
+            timestamp: header.timestamp(),
+            proto: header.proto(),
+            validation_pass: header.validation_pass(),
+            operations_hash: hex::encode(header.operations_hash()),
+            fitness: header.fitness().iter().map(hex::encode).collect(),
+            context: hex::encode(header.context()),
+        })
+    }
+}
+
+const CSV_COLUMNS: &str =
+    "level,hash,predecessor,timestamp,proto,validation_pass,operations_hash,fitness,context";
+
+/// Writes one JSON object per line
+pub fn write_json<W>(mut writer: W, headers: &[BlockHeader]) -> Result<(), SocketError>
+where
+    W: io::Write,
+{
+    for header in headers {
+        let row = serde_json::to_string(&Row::new(header)?)
+            .map_err(|_| SocketError::EncodingError)?;
+        writeln!(writer, "{}", row).map_err(SocketError::Io)?;
+    }
+    Ok(())
+}
+
+/// Writes the column names and one line per header, the fitness elements are separated by `:`
+pub fn write_csv<W>(mut writer: W, headers: &[BlockHeader]) -> Result<(), SocketError>
+where
+    W: io::Write,
+{
+    writeln!(writer, "{}", CSV_COLUMNS).map_err(SocketError::Io)?;
+    for header in headers {
+        let row = Row::new(header)?;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{}",
+            row.level,
+            row.hash,
+            row.predecessor,
+            row.timestamp,
+            row.proto,
+            row.validation_pass,
+            row.operations_hash,
+            row.fitness.join(":"),
+            row.context,
+        )
+        .map_err(SocketError::Io)?;
+    }
+    Ok(())
+}
@@ -2,6 +2,7 @@ use tokio::io;
 use failure::Fail;
 use crypto::crypto_box::CryptoError;
 use tezos_messages::p2p::binary_message::BinaryChunkError;
+use crypto::hash::Hash;
 
 #[derive(Debug, Fail)]
 pub enum SocketError {
@@ -19,4 +20,95 @@ pub enum SocketError {
     Decryption(CryptoError),
     #[fail(display = "chunk error {}", _0)]
     Chunk(BinaryChunkError),
+    #[fail(display = "sync error {}", _0)]
+    Sync(SyncError),
+    #[fail(display = "storage error {}", _0)]
+    Storage(StorageError),
+    #[fail(display = "export error {}", _0)]
+    Export(ExportError),
+}
+
+/// The peer sent data which does not fit the chain
+#[derive(Debug, Fail)]
+pub enum SyncError {
+    #[fail(display = "received header {:x?} which was not requested", received)]
+    UnrequestedHeader { received: Hash },
+    #[fail(display = "level {} follows level {}", level, predecessor)]
+    Level { level: i32, predecessor: i32 },
+    #[fail(display = "timestamp of level {} is not after the predecessor", level)]
+    Timestamp { level: i32 },
+    #[fail(display = "timestamp of level {} is in the future", level)]
+    FutureTimestamp { level: i32 },
+    #[fail(display = "fitness of level {} is lower than the predecessor", level)]
+    Fitness { level: i32 },
+    #[fail(display = "the chain ends at unknown genesis")]
+    Genesis,
+    #[fail(display = "the peer did not answer the request in time")]
+    Timeout,
+    #[fail(
+        display = "received operations of {:x?} pass {} which were not requested",
+        received, validation_pass
+    )]
+    UnrequestedOperations { received: Hash, validation_pass: i8 },
+    #[fail(display = "operations of level {} do not match the operations hash", level)]
+    OperationsHash { level: i32 },
+    #[fail(
+        display = "path of operations of level {} pass {} leads to another pass",
+        level, validation_pass
+    )]
+    OperationsPath { level: i32, validation_pass: i8 },
+    #[fail(display = "the branch does not contain the checkpoint at level {}", level)]
+    Checkpoint { level: i32 },
+    #[fail(display = "received protocol {:x?} which was not requested", received)]
+    UnrequestedProtocol { received: Hash },
+}
+
+#[derive(Debug, Fail)]
+pub enum DecodeError {
+    #[fail(display = "io error {}", _0)]
+    Io(io::Error),
+    #[fail(display = "unsupported capture format")]
+    UnsupportedFormat,
+    #[fail(display = "unsupported link type {}", _0)]
+    UnsupportedLinkType(u32),
+    #[fail(display = "no tcp conversation in the capture")]
+    NoConversation,
+    #[fail(display = "connection messages are missing")]
+    NoConnectionMessage,
+    #[fail(display = "identity does not match the conversation")]
+    Identity,
+}
+
+#[derive(Debug, Fail)]
+pub enum StorageError {
+    #[fail(display = "io error {}", _0)]
+    Io(io::Error),
+    #[fail(display = "encoding error")]
+    Encoding,
+    #[fail(display = "decoding error")]
+    Decoding,
+    #[fail(display = "the block is not stored")]
+    UnknownBlock,
+}
+
+#[derive(Debug, Fail)]
+pub enum ExportError {
+    #[fail(display = "io error {}", _0)]
+    Io(io::Error),
+    #[fail(display = "not a chain export")]
+    UnsupportedFormat,
+    #[fail(display = "unsupported export version {}", _0)]
+    UnsupportedVersion(u16),
+    #[fail(display = "unknown frame kind {}", _0)]
+    UnknownFrame(u8),
+    #[fail(display = "frame of {} bytes is too large", _0)]
+    FrameTooLarge(u32),
+    #[fail(display = "the export ends unexpectedly")]
+    Truncated,
+    #[fail(display = "checksum mismatch")]
+    Checksum,
+    #[fail(display = "encoding error")]
+    Encoding,
+    #[fail(display = "decoding error")]
+    Decoding,
 }
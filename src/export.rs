@@ -0,0 +1,313 @@
+//! Framed chain export which is written and read one header at a time
+//!
+//! The file starts with the magic and the version. Then go frames, each frame is
+//! the kind byte, the big-endian `u32` length of the payload and the payload.
+//! Records carry an encoded header, every `BLOCK_SIZE` records are followed by
+//! a checksum frame with the blake2b digest of their payloads. The index frame goes last,
+//! it lists the offset and the first level of every block, the file ends with
+//! the offset of the index frame and the magic, so the index is found from the end.
+
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use tezos_messages::p2p::{binary_message::BinaryMessage, encoding::block_header::BlockHeader};
+use crypto::blake2b;
+use super::{error::ExportError, bootstrap::read_dump};
+
+const MAGIC: &[u8; 8] = b"TZCHAIN\0";
+const VERSION: u16 = 1;
+/// number of records covered by one checksum
+const BLOCK_SIZE: usize = 1024;
+/// longest payload of a frame, the index of a billion headers still fits
+const MAX_FRAME: u32 = 1 << 24;
+
+const RECORD: u8 = 1;
+const CHECKSUM: u8 = 2;
+const INDEX: u8 = 3;
+
+/// Where the block of records starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub level: i32,
+    pub offset: u64,
+    pub count: u32,
+}
+
+impl IndexEntry {
+    const SIZE: usize = 16;
+
+    fn to_bytes(&self) -> [u8; IndexEntry::SIZE] {
+        let mut bytes = [0; IndexEntry::SIZE];
+        bytes[..4].copy_from_slice(&self.level.to_be_bytes());
+        bytes[4..12].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[12..].copy_from_slice(&self.count.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        IndexEntry {
+            level: i32::from_be_bytes(<[u8; 4]>::try_from(&bytes[..4]).unwrap()),
+            offset: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[4..12]).unwrap()),
+            count: u32::from_be_bytes(<[u8; 4]>::try_from(&bytes[12..16]).unwrap()),
+        }
+    }
+}
+
+pub struct ExportWriter<W>
+where
+    W: Write,
+{
+    inner: W,
+    offset: u64,
+    /// payloads of the records since the last checksum
+    block: Vec<u8>,
+    index: Vec<IndexEntry>,
+}
+
+impl<W> ExportWriter<W>
+where
+    W: Write,
+{
+    pub fn new(mut inner: W) -> Result<Self, ExportError> {
+        inner.write_all(MAGIC).map_err(ExportError::Io)?;
+        inner
+            .write_all(&VERSION.to_be_bytes())
+            .map_err(ExportError::Io)?;
+        Ok(ExportWriter {
+            inner: inner,
+            offset: (MAGIC.len() + 2) as u64,
+            block: Vec::new(),
+            index: Vec::new(),
+        })
+    }
+
+    pub fn push(&mut self, header: &BlockHeader) -> Result<(), ExportError> {
+        let bytes = header.as_bytes().map_err(|_| ExportError::Encoding)?;
+        match self.index.last_mut() {
+            Some(entry) if (entry.count as usize) < BLOCK_SIZE => entry.count += 1,
+            _ => self.index.push(IndexEntry {
+                level: header.level(),
+                offset: self.offset,
+                count: 1,
+            }),
+        }
+        self.frame(RECORD, &bytes)?;
+        self.block.extend_from_slice(&bytes);
+        if self.index.last().map(|entry| entry.count as usize) == Some(BLOCK_SIZE) {
+            self.checksum()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the trailing checksum and the index, returns the underlying writer
+    pub fn finish(mut self) -> Result<W, ExportError> {
+        if !self.block.is_empty() {
+            self.checksum()?;
+        }
+        let index_offset = self.offset;
+        let index = self
+            .index
+            .iter()
+            .flat_map(|entry| entry.to_bytes().to_vec())
+            .collect::<Vec<_>>();
+        self.frame(INDEX, &index)?;
+        self.inner
+            .write_all(&index_offset.to_be_bytes())
+            .map_err(ExportError::Io)?;
+        self.inner.write_all(MAGIC).map_err(ExportError::Io)?;
+        self.inner.flush().map_err(ExportError::Io)?;
+        Ok(self.inner)
+    }
+
+    fn checksum(&mut self) -> Result<(), ExportError> {
+        let digest = blake2b::digest_256(&self.block);
+        self.block.clear();
+        self.frame(CHECKSUM, &digest)
+    }
+
+    fn frame(&mut self, kind: u8, payload: &[u8]) -> Result<(), ExportError> {
+        let length = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+        if length > MAX_FRAME {
+            return Err(ExportError::FrameTooLarge(length));
+        }
+        self.inner.write_all(&[kind]).map_err(ExportError::Io)?;
+        self.inner
+            .write_all(&length.to_be_bytes())
+            .map_err(ExportError::Io)?;
+        self.inner.write_all(payload).map_err(ExportError::Io)?;
+        self.offset += (5 + payload.len()) as u64;
+        Ok(())
+    }
+}
+
+/// Iterates over the headers, checks every block of records once it is read
+pub struct ExportReader<R>
+where
+    R: Read,
+{
+    inner: R,
+    block: Vec<u8>,
+    finished: bool,
+}
+
+impl<R> ExportReader<R>
+where
+    R: Read,
+{
+    pub fn new(mut inner: R) -> Result<Self, ExportError> {
+        let mut magic = [0; 8];
+        inner.read_exact(&mut magic).map_err(ExportError::Io)?;
+        if &magic != MAGIC {
+            return Err(ExportError::UnsupportedFormat);
+        }
+        let mut version = [0; 2];
+        inner.read_exact(&mut version).map_err(ExportError::Io)?;
+        match u16::from_be_bytes(version) {
+            VERSION => (),
+            version => return Err(ExportError::UnsupportedVersion(version)),
+        }
+        Ok(ExportReader {
+            inner: inner,
+            block: Vec::new(),
+            finished: false,
+        })
+    }
+
+    fn next_header(&mut self) -> Result<Option<BlockHeader>, ExportError> {
+        loop {
+            match read_frame(&mut self.inner)? {
+                (RECORD, payload) => {
+                    self.block.extend_from_slice(&payload);
+                    let header =
+                        BlockHeader::from_bytes(payload).map_err(|_| ExportError::Decoding)?;
+                    return Ok(Some(header));
+                },
+                (CHECKSUM, digest) => {
+                    if blake2b::digest_256(&self.block) != digest {
+                        return Err(ExportError::Checksum);
+                    }
+                    self.block.clear();
+                },
+                (INDEX, _) => {
+                    if !self.block.is_empty() {
+                        // records after the last checksum
+                        return Err(ExportError::Checksum);
+                    }
+                    return Ok(None);
+                },
+                (kind, _) => return Err(ExportError::UnknownFrame(kind)),
+            }
+        }
+    }
+}
+
+impl<R> Iterator for ExportReader<R>
+where
+    R: Read,
+{
+    type Item = Result<BlockHeader, ExportError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let item = self.next_header().transpose();
+        if !matches!(item, Some(Ok(_))) {
+            self.finished = true;
+        }
+        item
+    }
+}
+
+/// Reads the index from the end of the export without reading the records
+pub fn read_index<R>(mut inner: R) -> Result<Vec<IndexEntry>, ExportError>
+where
+    R: Read + Seek,
+{
+    let mut trailer = [0; 16];
+    inner.seek(SeekFrom::End(-16)).map_err(ExportError::Io)?;
+    inner.read_exact(&mut trailer).map_err(truncated)?;
+    if &trailer[8..] != MAGIC {
+        return Err(ExportError::Truncated);
+    }
+    let offset = u64::from_be_bytes(<[u8; 8]>::try_from(&trailer[..8]).unwrap());
+    inner.seek(SeekFrom::Start(offset)).map_err(ExportError::Io)?;
+    let payload = match read_frame(&mut inner)? {
+        (INDEX, payload) => payload,
+        (kind, _) => return Err(ExportError::UnknownFrame(kind)),
+    };
+    Ok(payload
+        .chunks_exact(IndexEntry::SIZE)
+        .map(IndexEntry::from_bytes)
+        .collect())
+}
+
+/// Converts the `Chain` dump into the export, returns the number of headers
+pub fn convert_dump<P, Q>(dump: P, export: Q) -> Result<usize, ExportError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let headers = read_dump(dump).map_err(|_| ExportError::Decoding)?;
+    let file = File::create(export).map_err(ExportError::Io)?;
+    let mut writer = ExportWriter::new(BufWriter::new(file))?;
+    // the dump goes from the head, the export goes from the genesis
+    for header in headers.iter().rev() {
+        writer.push(header)?;
+    }
+    writer.finish()?;
+    Ok(headers.len())
+}
+
+pub fn open<P>(path: P) -> Result<ExportReader<BufReader<File>>, ExportError>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(path).map_err(ExportError::Io)?;
+    ExportReader::new(BufReader::new(file))
+}
+
+/// Reads either the export or the `Chain` dump, headers go in the order of the file
+pub fn read_headers<P>(path: P) -> Result<Vec<BlockHeader>, ExportError>
+where
+    P: AsRef<Path>,
+{
+    let mut file = File::open(path.as_ref()).map_err(ExportError::Io)?;
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    file.by_ref()
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .map_err(ExportError::Io)?;
+    if magic.as_slice() == MAGIC {
+        file.seek(SeekFrom::Start(0)).map_err(ExportError::Io)?;
+        ExportReader::new(BufReader::new(file))?.collect()
+    } else {
+        read_dump(path).map_err(|_| ExportError::Decoding)
+    }
+}
+
+/// Reads the kind and the payload, refuses the length above `MAX_FRAME` before allocating
+fn read_frame<R>(inner: &mut R) -> Result<(u8, Vec<u8>), ExportError>
+where
+    R: Read,
+{
+    let mut prefix = [0; 5];
+    inner.read_exact(&mut prefix).map_err(truncated)?;
+    let length = u32::from_be_bytes(<[u8; 4]>::try_from(&prefix[1..]).unwrap());
+    if length > MAX_FRAME {
+        return Err(ExportError::FrameTooLarge(length));
+    }
+    let mut payload = vec![0; length as usize];
+    inner.read_exact(&mut payload).map_err(truncated)?;
+    Ok((prefix[0], payload))
+}
+
+fn truncated(error: io::Error) -> ExportError {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => ExportError::Truncated,
+        _ => ExportError::Io(error),
+    }
+}
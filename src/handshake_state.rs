@@ -1,4 +1,4 @@
-use std::{mem, convert::TryFrom};
+use std::{mem, slice, convert::TryFrom};
 use tokio::{
     net::TcpStream,
     io::{AsyncReadExt, AsyncWriteExt},
@@ -17,16 +17,19 @@ use super::{error::SocketError, decipher_state::DecipherState};
 pub enum HandshakeState {
     Connection,
     Metadata(DecipherState),
-    Acknowledge(DecipherState),
-    Finish(DecipherState, AckMessage),
+    // the metadata of the peer
+    Acknowledge(DecipherState, MetadataMessage),
+    Finish(DecipherState, AckMessage, MetadataMessage),
     Awaiting,
 }
 
 impl HandshakeState {
+    /// The `metadata` is sent to the peer
     pub async fn run(
         &mut self,
         logger: &Logger,
         stream: &mut TcpStream,
+        metadata: &MetadataMessage,
     ) -> Result<(), SocketError> {
         let current_state = mem::replace(self, HandshakeState::Awaiting);
         let new_state = match current_state {
@@ -37,22 +40,27 @@ impl HandshakeState {
                 HandshakeState::Metadata(decipher)
             },
             HandshakeState::Metadata(mut decipher) => {
-                let m = MetadataMessage::new(false, false);
-                decipher.write_message(stream, &[m]).await?;
-                let _ = decipher.read_chunk(stream).await?;
+                decipher
+                    .write_message(stream, slice::from_ref(metadata))
+                    .await?;
+                let data = decipher.read_chunk(stream).await?;
+                let peer_metadata =
+                    MetadataMessage::from_bytes(data).map_err(|_| SocketError::DecodingError)?;
 
                 slog::info!(logger, "exchanged metadata messages");
-                HandshakeState::Acknowledge(decipher)
+                HandshakeState::Acknowledge(decipher, peer_metadata)
             },
-            HandshakeState::Acknowledge(mut decipher) => {
+            HandshakeState::Acknowledge(mut decipher, peer_metadata) => {
                 decipher.write_message(stream, &[AckMessage::Ack]).await?;
                 let data = decipher.read_chunk(stream).await?;
                 let ack = AckMessage::from_bytes(data).map_err(|_| SocketError::DecodingError)?;
 
                 slog::info!(logger, "exchanged acknowledge messages");
-                HandshakeState::Finish(decipher, ack)
+                HandshakeState::Finish(decipher, ack, peer_metadata)
+            },
+            HandshakeState::Finish(decipher, ack, peer_metadata) => {
+                HandshakeState::Finish(decipher, ack, peer_metadata)
             },
-            HandshakeState::Finish(decipher, ack) => HandshakeState::Finish(decipher, ack),
             HandshakeState::Awaiting => HandshakeState::Awaiting,
         };
         let _ = mem::replace(self, new_state);
@@ -63,6 +71,41 @@ impl HandshakeState {
 async fn outgoing_connection(stream: &mut TcpStream) -> Result<DecipherState, SocketError> {
     let identity = Identity::from_path("identity.json".to_string()).unwrap();
 
+    let initiator_chunk = connection_chunk(&identity)?;
+    stream
+        .write_all(initiator_chunk.raw())
+        .await
+        .map_err(SocketError::Io)?;
+    let responder_chunk = read_connection_chunk(stream).await?;
+
+    let decipher = identity
+        .decipher(initiator_chunk.raw(), responder_chunk.raw())
+        .ok()
+        .unwrap();
+    Ok(DecipherState::new(decipher, true))
+}
+
+/// Responder side of the connection message exchange, only the mock peer answers connections
+#[cfg(any(test, feature = "testing"))]
+pub async fn incoming_connection(
+    stream: &mut TcpStream,
+    identity: &Identity,
+) -> Result<DecipherState, SocketError> {
+    let initiator_chunk = read_connection_chunk(stream).await?;
+    let responder_chunk = connection_chunk(identity)?;
+    stream
+        .write_all(responder_chunk.raw())
+        .await
+        .map_err(SocketError::Io)?;
+
+    let decipher = identity
+        .decipher(initiator_chunk.raw(), responder_chunk.raw())
+        .ok()
+        .unwrap();
+    Ok(DecipherState::new(decipher, false))
+}
+
+pub(crate) fn connection_chunk(identity: &Identity) -> Result<BinaryChunk, SocketError> {
     let chain_name = "TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string();
     let version = NetworkVersion::new(chain_name, 0, 1);
     let connection_message = ConnectionMessage {
@@ -75,12 +118,10 @@ async fn outgoing_connection(stream: &mut TcpStream) -> Result<DecipherState, So
     let chunk = connection_message
         .as_bytes()
         .map_err(|_| SocketError::EncodingError)?;
-    let initiator_chunk = BinaryChunk::from_content(chunk.as_ref()).unwrap();
-    stream
-        .write_all(initiator_chunk.raw())
-        .await
-        .map_err(SocketError::Io)?;
+    Ok(BinaryChunk::from_content(chunk.as_ref()).unwrap())
+}
 
+async fn read_connection_chunk(stream: &mut TcpStream) -> Result<BinaryChunk, SocketError> {
     let mut size_buf = [0; 2];
     stream
         .read_exact(size_buf.as_mut())
@@ -93,11 +134,5 @@ async fn outgoing_connection(stream: &mut TcpStream) -> Result<DecipherState, So
         .read_exact(&mut chunk[2..])
         .await
         .map_err(SocketError::Io)?;
-    let responder_chunk = BinaryChunk::try_from(chunk).unwrap();
-
-    let decipher = identity
-        .decipher(initiator_chunk.raw(), responder_chunk.raw())
-        .ok()
-        .unwrap();
-    Ok(DecipherState::new(decipher, true))
+    Ok(BinaryChunk::try_from(chunk).unwrap())
 }
@@ -1,4 +1,5 @@
 mod error;
+mod config;
 
 mod socket;
 mod socket_state;
@@ -7,5 +8,23 @@ mod decipher_state;
 mod read_message_state;
 mod trusted_connection;
 mod bootstrap;
+mod storage;
 
-pub use self::{error::SocketError, socket::Socket};
+mod pcap;
+pub mod decode;
+pub mod dump;
+pub mod export;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use self::{
+    error::{SocketError, SyncError, StorageError, DecodeError, ExportError},
+    config::Config,
+    bootstrap::{
+        SharedChain, Reorg, TrustedCheckpoint, MempoolObserver, Observed, block_hash, read_dump,
+        write_dump, merkle,
+    },
+    storage::{HeaderStore, Checkpoint},
+    socket::Socket,
+};
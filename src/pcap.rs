@@ -0,0 +1,370 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+use super::error::DecodeError;
+
+/// Bytes sent by one side of the conversation, reassembled from tcp segments
+#[derive(Default)]
+pub struct Stream {
+    pub data: Vec<u8>,
+    /// end offset of each reassembled segment and its capture time in microseconds
+    pub marks: Vec<(usize, u64)>,
+}
+
+impl Stream {
+    pub fn new(data: Vec<u8>) -> Self {
+        Stream {
+            data: data,
+            marks: Vec::new(),
+        }
+    }
+
+    /// The time when the byte at `end - 1` was captured
+    pub fn time(&self, end: usize) -> Option<u64> {
+        let i = match self.marks.binary_search_by_key(&end, |&(e, _)| e) {
+            Ok(i) => i,
+            Err(i) => i,
+        };
+        self.marks.get(i).map(|&(_, time)| time)
+    }
+}
+
+pub struct Flow {
+    pub initiator: Stream,
+    pub responder: Stream,
+}
+
+/// Reads a libpcap file and reassembles the first tcp conversation found there
+pub fn read_flow<P>(path: P) -> Result<Flow, DecodeError>
+where
+    P: AsRef<Path>,
+{
+    let data = fs::read(path).map_err(DecodeError::Io)?;
+    parse(&data)
+}
+
+fn parse(data: &[u8]) -> Result<Flow, DecodeError> {
+    let (header, mut records) = (data.get(..24), data.get(24..).unwrap_or(&[]));
+    let header = header.ok_or(DecodeError::UnsupportedFormat)?;
+    let (big_endian, nanos) = match &header[..4] {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+        _ => return Err(DecodeError::UnsupportedFormat),
+    };
+    let read_u32 = |b: &[u8]| {
+        let b = <[u8; 4]>::try_from(&b[..4]).unwrap();
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    let link_type = read_u32(&header[20..]);
+
+    let mut endpoints = None::<(SocketAddr, SocketAddr)>;
+    let mut initiator = Reassembly::default();
+    let mut responder = Reassembly::default();
+    while records.len() >= 16 {
+        let seconds = read_u32(&records[0..]) as u64;
+        let fraction = read_u32(&records[4..]) as u64;
+        let length = read_u32(&records[8..]) as usize;
+        let frame = records
+            .get(16..(16 + length))
+            .ok_or(DecodeError::UnsupportedFormat)?;
+        records = &records[(16 + length)..];
+        let time = seconds * 1_000_000 + if nanos { fraction / 1000 } else { fraction };
+
+        let segment = match link(link_type, frame)?.and_then(|(ip, v6)| network(ip, v6)) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        // the first tcp segment defines the conversation, SYN+ACK is sent by the responder
+        let (i, r) = *endpoints.get_or_insert_with(|| {
+            if segment.syn && segment.ack {
+                (segment.destination, segment.source)
+            } else {
+                (segment.source, segment.destination)
+            }
+        });
+        if i == segment.source && r == segment.destination {
+            initiator.push(&segment, time);
+        } else if r == segment.source && i == segment.destination {
+            responder.push(&segment, time);
+        }
+    }
+
+    if endpoints.is_none() {
+        return Err(DecodeError::NoConversation);
+    }
+    Ok(Flow {
+        initiator: initiator.stream,
+        responder: responder.stream,
+    })
+}
+
+/// Strips the link layer, returns the ip packet and whether it is ipv6
+fn link(link_type: u32, frame: &[u8]) -> Result<Option<(&[u8], bool)>, DecodeError> {
+    let packet = match link_type {
+        // BSD loopback
+        0 | 108 => frame.get(4..).and_then(by_version),
+        // Ethernet
+        1 => match frame.get(12..14) {
+            Some([0x81, 0x00]) => by_ether_type(frame.get(16..18), frame.get(18..)),
+            ether_type => by_ether_type(ether_type, frame.get(14..)),
+        },
+        // raw ip
+        12 | 101 => by_version(frame),
+        // Linux cooked capture
+        113 => by_ether_type(frame.get(14..16), frame.get(16..)),
+        t => return Err(DecodeError::UnsupportedLinkType(t)),
+    };
+    Ok(packet)
+}
+
+fn by_version(packet: &[u8]) -> Option<(&[u8], bool)> {
+    match packet.first().map(|b| b >> 4) {
+        Some(4) => Some((packet, false)),
+        Some(6) => Some((packet, true)),
+        _ => None,
+    }
+}
+
+fn by_ether_type<'a>(
+    ether_type: Option<&[u8]>,
+    packet: Option<&'a [u8]>,
+) -> Option<(&'a [u8], bool)> {
+    match ether_type? {
+        [0x08, 0x00] => Some((packet?, false)),
+        [0x86, 0xdd] => Some((packet?, true)),
+        _ => None,
+    }
+}
+
+struct Segment<'a> {
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+    syn: bool,
+    ack: bool,
+    payload: &'a [u8],
+}
+
+fn network(packet: &[u8], v6: bool) -> Option<Segment<'_>> {
+    let (source, destination, tcp) = if v6 {
+        let length = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+        let source = <[u8; 16]>::try_from(packet.get(8..24)?).ok()?;
+        let destination = <[u8; 16]>::try_from(packet.get(24..40)?).ok()?;
+        let offset = skip_extensions(packet, *packet.get(6)?, 40)?;
+        (
+            IpAddr::V6(Ipv6Addr::from(source)),
+            IpAddr::V6(Ipv6Addr::from(destination)),
+            packet.get(offset..(40 + length))?,
+        )
+    } else {
+        if *packet.get(9)? != 6 {
+            return None;
+        }
+        let header_length = ((packet[0] & 0x0f) as usize) * 4;
+        let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        let source = <[u8; 4]>::try_from(packet.get(12..16)?).ok()?;
+        let destination = <[u8; 4]>::try_from(packet.get(16..20)?).ok()?;
+        (
+            IpAddr::V4(Ipv4Addr::from(source)),
+            IpAddr::V4(Ipv4Addr::from(destination)),
+            packet.get(header_length..length)?,
+        )
+    };
+
+    let source_port = u16::from_be_bytes([*tcp.get(0)?, *tcp.get(1)?]);
+    let destination_port = u16::from_be_bytes([*tcp.get(2)?, *tcp.get(3)?]);
+    let sequence = u32::from_be_bytes(<[u8; 4]>::try_from(tcp.get(4..8)?).ok()?);
+    let data_offset = ((*tcp.get(12)? >> 4) as usize) * 4;
+    let flags = *tcp.get(13)?;
+    Some(Segment {
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        sequence: sequence,
+        syn: flags & 0x02 != 0,
+        ack: flags & 0x10 != 0,
+        payload: tcp.get(data_offset..)?,
+    })
+}
+
+/// Skips ipv6 extension headers, returns the offset of the tcp header,
+/// none if the packet is not tcp or is a fragment, fragments are not reassembled
+fn skip_extensions(packet: &[u8], mut next: u8, mut offset: usize) -> Option<usize> {
+    loop {
+        match next {
+            6 => return Some(offset),
+            // hop-by-hop options, routing, destination options
+            0 | 43 | 60 => {
+                let length = (*packet.get(offset + 1)? as usize + 1) * 8;
+                next = *packet.get(offset)?;
+                offset += length;
+            },
+            // fragment, only the unfragmented packet with the header is accepted
+            44 => {
+                // the fragment offset and the more fragments flag
+                let fragment = [*packet.get(offset + 2)?, *packet.get(offset + 3)?];
+                if u16::from_be_bytes(fragment) != 0 {
+                    return None;
+                }
+                next = *packet.get(offset)?;
+                offset += 8;
+            },
+            // authentication header, its length is in 4-byte units
+            51 => {
+                let length = (*packet.get(offset + 1)? as usize + 2) * 4;
+                next = *packet.get(offset)?;
+                offset += length;
+            },
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Reassembly {
+    next: Option<u32>,
+    pending: BTreeMap<u32, (Vec<u8>, u64)>,
+    stream: Stream,
+}
+
+impl Reassembly {
+    fn push(&mut self, segment: &Segment, time: u64) {
+        if segment.syn {
+            self.next = Some(segment.sequence.wrapping_add(1));
+            return;
+        }
+        if segment.payload.is_empty() {
+            return;
+        }
+        self.next.get_or_insert(segment.sequence);
+        self.pending
+            .insert(segment.sequence, (segment.payload.to_vec(), time));
+
+        // apply every pending segment that became contiguous, retransmissions are trimmed
+        loop {
+            let next = self.next.unwrap();
+            let ready = self
+                .pending
+                .keys()
+                .cloned()
+                .find(|&sequence| (sequence.wrapping_sub(next) as i32) <= 0);
+            let sequence = match ready {
+                Some(sequence) => sequence,
+                None => break,
+            };
+            let (payload, time) = self.pending.remove(&sequence).unwrap();
+            let skip = next.wrapping_sub(sequence) as usize;
+            if skip < payload.len() {
+                self.stream.data.extend_from_slice(&payload[skip..]);
+                self.stream.marks.push((self.stream.data.len(), time));
+                self.next = Some(next.wrapping_add((payload.len() - skip) as u32));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+    const PSH: u8 = 0x08;
+
+    /// Little-endian libpcap with microsecond timestamps
+    fn capture(link_type: u32, frames: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&0xffffu32.to_le_bytes());
+        data.extend_from_slice(&link_type.to_le_bytes());
+        for (time, frame) in frames {
+            data.extend_from_slice(&((time / 1_000_000) as u32).to_le_bytes());
+            data.extend_from_slice(&((time % 1_000_000) as u32).to_le_bytes());
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    fn tcp(ports: (u16, u16), sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&ports.0.to_be_bytes());
+        segment.extend_from_slice(&ports.1.to_be_bytes());
+        segment.extend_from_slice(&sequence.to_be_bytes());
+        // the acknowledgment number, the data offset of five words and the flags
+        segment.extend_from_slice(&[0, 0, 0, 0, 5 << 4, flags]);
+        // the window, the checksum and the urgent pointer
+        segment.extend_from_slice(&[0; 6]);
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn ethernet_ipv4(source: u8, destination: u8, tcp: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 0]);
+        frame.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 64, 6, 0, 0]);
+        frame.extend_from_slice(&[10, 0, 0, source, 10, 0, 0, destination]);
+        frame.extend_from_slice(tcp);
+        frame
+    }
+
+    fn ipv6(next: u8, extensions: &[u8], tcp: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&((extensions.len() + tcp.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[next, 64]);
+        packet.extend_from_slice(&[0; 15]);
+        packet.push(1);
+        packet.extend_from_slice(&[0; 15]);
+        packet.push(2);
+        packet.extend_from_slice(extensions);
+        packet.extend_from_slice(tcp);
+        packet
+    }
+
+    #[test]
+    fn reassembly() {
+        let (a, b) = ((5000, 9732), (9732, 5000));
+        let frames = vec![
+            (1, ethernet_ipv4(1, 2, &tcp(a, 99, SYN, b""))),
+            (2, ethernet_ipv4(2, 1, &tcp(b, 499, SYN | ACK, b""))),
+            (3, ethernet_ipv4(1, 2, &tcp(a, 100, PSH | ACK, b"hel"))),
+            (4, ethernet_ipv4(2, 1, &tcp(b, 500, PSH | ACK, b"ok"))),
+            // the chunk is split across segments, which arrive out of order
+            (5, ethernet_ipv4(1, 2, &tcp(a, 108, PSH | ACK, b"rld"))),
+            (6, ethernet_ipv4(1, 2, &tcp(a, 103, PSH | ACK, b"lo wo"))),
+            // retransmission
+            (7, ethernet_ipv4(1, 2, &tcp(a, 100, PSH | ACK, b"hello"))),
+        ];
+        let flow = parse(&capture(1, &frames)).unwrap();
+        assert_eq!(flow.initiator.data, b"hello world".to_vec());
+        assert_eq!(flow.initiator.marks, vec![(3, 3), (8, 6), (11, 5)]);
+        assert_eq!(flow.initiator.time(5), Some(6));
+        assert_eq!(flow.responder.data, b"ok".to_vec());
+    }
+
+    #[test]
+    fn ipv6_extensions() {
+        let a = (5000, 9732);
+        // hop-by-hop options, then destination options, then tcp
+        let extensions = [60, 0, 1, 4, 0, 0, 0, 0, 6, 0, 1, 4, 0, 0, 0, 0];
+        // the second fragment of a packet
+        let fragment = [6, 0, 0, 0x09, 0, 0, 0, 1];
+        let frames = vec![
+            (1, ipv6(0, &extensions, &tcp(a, 100, PSH | ACK, b"abc"))),
+            (2, ipv6(44, &fragment, &tcp(a, 103, PSH | ACK, b"zzz"))),
+            (3, ipv6(6, &[], &tcp(a, 103, PSH | ACK, b"def"))),
+        ];
+        let flow = parse(&capture(101, &frames)).unwrap();
+        assert_eq!(flow.initiator.data, b"abcdef".to_vec());
+    }
+}
@@ -1,19 +1,29 @@
-use std::{mem, fmt};
-use tokio::net::TcpStream;
+use std::{fmt, io, marker::PhantomData};
+use tokio::io::AsyncReadExt;
 use slog::Logger;
 use tezos_messages::p2p::binary_message::BinaryMessage;
 use tezos_encoding::binary_reader::BinaryReaderError;
 use super::{error::SocketError, decipher_state::DecipherState};
 
-pub enum ReadMessageState<M>
+/// Size of the chunk length prefix
+const CHUNK_SIZE_PREFIX: usize = 2;
+
+/// Assembles messages from encrypted chunks
+///
+/// Everything read from the stream is kept in the state, and the only await point
+/// is a single `read`, so the future of `read_message` might be dropped at any time,
+/// for example in `select!`, the next call continues where the dropped one stopped.
+pub struct ReadMessageState<M>
 where
     M: BinaryMessage,
 {
-    Empty,
-    Unknown { buffer: Vec<u8> },
-    Buffering { remaining: usize, buffer: Vec<u8> },
-    HasMessage(M),
-    Awaiting,
+    /// bytes read from the stream which do not make a complete chunk yet
+    raw: Vec<u8>,
+    /// decrypted content of the message so far
+    buffer: Vec<u8>,
+    /// how many bytes the message lacks, zero if it is not known yet
+    remaining: usize,
+    phantom: PhantomData<M>,
 }
 
 impl<M> ReadMessageState<M>
@@ -21,86 +31,71 @@ where
     M: BinaryMessage + fmt::Debug,
 {
     pub fn new() -> Self {
-        ReadMessageState::Unknown { buffer: Vec::new() }
+        ReadMessageState {
+            raw: Vec::new(),
+            buffer: Vec::new(),
+            remaining: 0,
+            phantom: PhantomData,
+        }
     }
 
-    pub async fn read_message(
+    pub async fn read_message<T>(
         &mut self,
         logger: &Logger,
-        stream: &mut TcpStream,
+        stream: &mut T,
         decipher: &mut DecipherState,
-    ) -> Result<M, SocketError> {
+    ) -> Result<M, SocketError>
+    where
+        T: Unpin + AsyncReadExt,
+    {
         loop {
-            if let Some(message) = self.try_read_message(logger, stream, decipher).await? {
+            if let Some(message) = self.try_decode()? {
+                slog::debug!(logger, "<- {:x?}", message);
                 break Ok(message);
             }
+            if let Some(chunk) = self.take_chunk() {
+                let data = decipher.decrypt(&chunk)?;
+                self.remaining = self.remaining.saturating_sub(data.len());
+                self.buffer.extend_from_slice(&data);
+                continue;
+            }
+            let mut data = [0; 0x1000];
+            let read = stream.read(&mut data).await.map_err(SocketError::Io)?;
+            if read == 0 {
+                let error = io::Error::from(io::ErrorKind::UnexpectedEof);
+                break Err(SocketError::Io(error));
+            }
+            self.raw.extend_from_slice(&data[..read]);
         }
     }
 
-    async fn try_read_message(
-        &mut self,
-        logger: &Logger,
-        stream: &mut TcpStream,
-        decipher: &mut DecipherState,
-    ) -> Result<Option<M>, SocketError> {
-        let current_state = mem::replace(self, ReadMessageState::Empty);
-        match current_state {
-            ReadMessageState::HasMessage(message) => {
-                slog::debug!(logger, "<- {:x?}", message);
+    /// Decodes the message once enough content is buffered
+    fn try_decode(&mut self) -> Result<Option<M>, SocketError> {
+        if self.buffer.is_empty() || self.remaining > 0 {
+            return Ok(None);
+        }
+        match M::from_bytes(&self.buffer) {
+            Ok(message) => {
+                self.buffer.clear();
                 Ok(Some(message))
             },
-            current_state => {
-                let _ = mem::replace(self, current_state);
-                self.run(stream, decipher).await?;
+            Err(BinaryReaderError::Underflow { bytes }) => {
+                self.remaining = bytes;
                 Ok(None)
             },
+            Err(_) => Err(SocketError::DecodingError),
         }
     }
 
-    async fn run(
-        &mut self,
-        stream: &mut TcpStream,
-        decipher: &mut DecipherState,
-    ) -> Result<(), SocketError> {
-        let current_state = mem::replace(self, ReadMessageState::Awaiting);
-        let new_state = match current_state {
-            ReadMessageState::Empty => {
-                let data = decipher.read_chunk(stream).await?;
-                // do no know if it is enough, the new state is Unknown
-                ReadMessageState::Unknown { buffer: data }
-            },
-            ReadMessageState::Unknown { buffer } => {
-                // check if it is enough, the new state is Buffering or HasMessage
-                match M::from_bytes(&buffer) {
-                    Ok(message) => ReadMessageState::HasMessage(message),
-                    Err(BinaryReaderError::Underflow { bytes }) => ReadMessageState::Buffering {
-                        remaining: bytes,
-                        buffer: buffer,
-                    },
-                    Err(_) => return Err(SocketError::DecodingError),
-                }
-            },
-            ReadMessageState::Buffering {
-                remaining,
-                mut buffer,
-            } => {
-                // add one more chunk
-                let chunk = decipher.read_chunk(stream).await?;
-                buffer.extend_from_slice(chunk.as_ref());
-                // if buffered what is remaining, move to Unknown, otherwise continue Buffering
-                if chunk.len() >= remaining {
-                    ReadMessageState::Unknown { buffer }
-                } else {
-                    ReadMessageState::Buffering {
-                        remaining: remaining - chunk.len(),
-                        buffer: buffer,
-                    }
-                }
-            },
-            ReadMessageState::HasMessage(message) => ReadMessageState::HasMessage(message),
-            ReadMessageState::Awaiting => ReadMessageState::Awaiting,
-        };
-        let _ = mem::replace(self, new_state);
-        Ok(())
+    /// Removes the content of the first chunk from the raw bytes if the chunk is complete
+    fn take_chunk(&mut self) -> Option<Vec<u8>> {
+        let prefix = self.raw.get(..CHUNK_SIZE_PREFIX)?;
+        let size = u16::from_be_bytes([prefix[0], prefix[1]]) as usize;
+        if self.raw.len() < CHUNK_SIZE_PREFIX + size {
+            return None;
+        }
+        let chunk = self.raw[CHUNK_SIZE_PREFIX..(CHUNK_SIZE_PREFIX + size)].to_vec();
+        self.raw.drain(..(CHUNK_SIZE_PREFIX + size));
+        Some(chunk)
     }
 }
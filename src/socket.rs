@@ -1,10 +1,17 @@
 use std::net::SocketAddr;
 use tokio::sync::oneshot;
 use slog::Logger;
-use super::{error::SocketError, socket_state::SocketState};
+use super::{
+    error::SocketError,
+    config::Config,
+    socket_state::SocketState,
+    bootstrap::SharedChain,
+};
 
 pub struct Socket {
     state: SocketState,
+    config: Config,
+    shared: SharedChain,
     shutdown_rx: oneshot::Receiver<()>,
 }
 
@@ -19,11 +26,14 @@ impl Shutdown {
 }
 
 impl Socket {
-    pub fn outgoing(address: SocketAddr) -> (Self, Shutdown) {
+    /// The `shared` chain might be downloaded by many sockets at once
+    pub fn outgoing(address: SocketAddr, config: Config, shared: SharedChain) -> (Self, Shutdown) {
         let (tx, rx) = oneshot::channel();
         (
             Socket {
                 state: SocketState::outgoing(address),
+                config: config,
+                shared: shared,
                 shutdown_rx: rx,
             },
             Shutdown { tx: tx },
@@ -35,7 +45,7 @@ impl Socket {
             // TODO:
             let _ = &self.shutdown_rx;
 
-            self.state.run(&logger).await.unwrap();
+            self.state.run(&logger, &self.config, &self.shared).await?;
             if let &SocketState::Finish = &self.state {
                 break Ok(());
             }
@@ -1,12 +1,13 @@
 use std::{net::SocketAddr, mem};
 use tokio::net::TcpStream;
-use tezos_messages::p2p::encoding::ack::AckMessage;
+use tezos_messages::p2p::encoding::{ack::AckMessage, metadata::MetadataMessage};
 use slog::Logger;
 use super::{
     error::SocketError,
+    config::Config,
     handshake_state::HandshakeState,
     trusted_connection::TrustedConnection,
-    bootstrap::{BootstrapState, genesis},
+    bootstrap::{BootstrapState, SharedChain, genesis},
 };
 
 /// The state of peer communication
@@ -24,7 +25,12 @@ impl SocketState {
         SocketState::Connecting(address)
     }
 
-    pub async fn run(&mut self, logger: &Logger) -> Result<(), SocketError> {
+    pub async fn run(
+        &mut self,
+        logger: &Logger,
+        config: &Config,
+        shared: &SharedChain,
+    ) -> Result<(), SocketError> {
         let state = mem::replace(self, SocketState::Awaiting);
         let state = match state {
             SocketState::Connecting(address) => {
@@ -35,15 +41,26 @@ impl SocketState {
                 SocketState::Handshake(stream, HandshakeState::Connection)
             },
             SocketState::Handshake(mut stream, mut state) => {
-                state.run(logger, &mut stream).await?;
+                // without the observer nobody would look at the mempool
+                let disable_mempool = config.disable_mempool || shared.mempool().is_none();
+                let metadata = MetadataMessage::new(disable_mempool, config.private_node);
+                state.run(logger, &mut stream, &metadata).await?;
                 match state {
-                    HandshakeState::Finish(decipher, ack) => {
-                        slog::info!(logger, "complete handshake {}", stream.peer_addr().unwrap());
+                    HandshakeState::Finish(decipher, ack, peer_metadata) => {
+                        let peer = stream.peer_addr().unwrap();
+                        slog::info!(logger, "complete handshake {}", peer);
                         match ack {
                             AckMessage::Ack => {
                                 slog::info!(logger, "ready to bootstrap");
-                                let connection = TrustedConnection::new(stream, decipher, &logger);
-                                let bootstrap = BootstrapState::new(connection, genesis::CHAIN_ID);
+                                let connection = TrustedConnection::new(
+                                    stream,
+                                    decipher,
+                                    peer_metadata,
+                                    &logger,
+                                );
+                                let chain_id = genesis::CHAIN_ID;
+                                let bootstrap =
+                                    BootstrapState::new(connection, peer, chain_id, config, shared);
                                 SocketState::BootstrapState(bootstrap)
                             },
                             AckMessage::Nack(info) => {
@@ -0,0 +1,472 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Write, Seek, SeekFrom},
+    ops::RangeBounds,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        block_header::BlockHeader,
+        operation::Operation,
+        operations_for_blocks::OperationsForBlocksMessage,
+        protocol::Protocol,
+    },
+};
+use crypto::hash::Hash;
+use super::{
+    error::StorageError,
+    bootstrap::{block_hash, merkle},
+};
+
+const HASH_SIZE: usize = 32;
+const DATA_FILE: &str = "headers.dat";
+const OPERATIONS_FILE: &str = "operations.dat";
+const TIP_FILE: &str = "tip";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const PROTOCOLS_DIRECTORY: &str = "protocols";
+
+/// Progress of an unfinished sync, the headers themselves are in the store
+pub struct Checkpoint {
+    /// the target of the sync
+    pub head: Hash,
+    /// blocks known only by hash from the remote branch, with guessed levels
+    pub anchors: Vec<(Hash, u32)>,
+}
+
+/// Append-only block header store indexed by hash and by level,
+/// the operations of the blocks are kept in another file next to the headers
+///
+/// Each record is the big-endian `u32` length of the encoded header, the block hash
+/// and the encoded header. An operations record is the length, the block hash,
+/// the validation pass and the encoded `OperationsForBlocksMessage`.
+/// Records are scanned one by one on open, those which fail to decode are skipped,
+/// a torn record after the last good one is cut away.
+/// Stored operations are also indexed by their hash.
+/// Protocols are stored one per file, named by the hex of the protocol hash.
+pub struct HeaderStore {
+    path: PathBuf,
+    file: File,
+    end: u64,
+    by_hash: HashMap<Hash, (u64, i32)>,
+    by_level: BTreeMap<i32, Vec<Hash>>,
+    operations_file: File,
+    operations_end: u64,
+    operations: HashMap<(Hash, i8), u64>,
+    /// the block, the validation pass and the position of the operation in the pass
+    by_operation: HashMap<Hash, (Hash, i8, usize)>,
+    tip: Option<Hash>,
+    unsynced: usize,
+    sync_every: usize,
+}
+
+impl HeaderStore {
+    /// Opens or creates the store in the directory, data is flushed to disk
+    /// after every `sync_every` headers and before the tip moves
+    pub fn open<P>(path: P, sync_every: usize) -> Result<Self, StorageError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(path.join(PROTOCOLS_DIRECTORY)).map_err(StorageError::Io)?;
+        let file = open_data(&path.join(DATA_FILE))?;
+        let operations_file = open_data(&path.join(OPERATIONS_FILE))?;
+
+        let mut store = HeaderStore {
+            path: path,
+            file: file,
+            end: 0,
+            by_hash: HashMap::new(),
+            by_level: BTreeMap::new(),
+            operations_file: operations_file,
+            operations_end: 0,
+            operations: HashMap::new(),
+            by_operation: HashMap::new(),
+            tip: None,
+            unsynced: 0,
+            sync_every: sync_every,
+        };
+        let data = store.file.try_clone().map_err(StorageError::Io)?;
+        let end = scan(&data, HASH_SIZE, |offset, hash, bytes| {
+            let header = match BlockHeader::from_bytes(bytes) {
+                Ok(header) => header,
+                Err(_) => return Ok(false),
+            };
+            if block_hash(&header).ok().as_deref() != Some(hash) {
+                return Ok(false);
+            }
+            store.index(hash.to_vec(), offset, header.level());
+            Ok(true)
+        })?;
+        store.end = cut(&mut store.file, end)?;
+
+        let data = store.operations_file.try_clone().map_err(StorageError::Io)?;
+        let end = scan(&data, HASH_SIZE + 1, |offset, key, bytes| {
+            let operations = match OperationsForBlocksMessage::from_bytes(bytes) {
+                Ok(operations) => operations,
+                Err(_) => return Ok(false),
+            };
+            let block = operations.operations_for_block();
+            let (hash, validation_pass) = (&key[..HASH_SIZE], key[HASH_SIZE] as i8);
+            if block.hash()[..] != hash[..] || *block.validation_pass() != validation_pass {
+                return Ok(false);
+            }
+            store.index_operations(&operations, offset)?;
+            Ok(true)
+        })?;
+        store.operations_end = cut(&mut store.operations_file, end)?;
+
+        store.tip = match fs::read(store.path.join(TIP_FILE)) {
+            Ok(tip) if store.by_hash.contains_key(&tip) => Some(tip),
+            _ => None,
+        };
+        Ok(store)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    /// Appends the header, returns false if it is already stored
+    pub fn append(&mut self, header: &BlockHeader) -> Result<bool, StorageError> {
+        let hash = block_hash(header).map_err(|_| StorageError::Encoding)?;
+        if self.contains(&hash) {
+            return Ok(false);
+        }
+        let bytes = header.as_bytes().map_err(|_| StorageError::Encoding)?;
+        let mut record = Vec::with_capacity(4 + HASH_SIZE + bytes.len());
+        record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        record.extend_from_slice(hash.as_ref());
+        record.extend_from_slice(bytes.as_ref());
+        self.file.write_all(&record).map_err(StorageError::Io)?;
+
+        let offset = self.end;
+        self.end += record.len() as u64;
+        self.index(hash, offset, header.level());
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.flush()?;
+        }
+        Ok(true)
+    }
+
+    /// Drops the header from the index, so it might be appended again,
+    /// the record stays in the file and is indexed again once the store is reopened
+    pub fn forget(&mut self, hash: &Hash) {
+        if let Some((_, level)) = self.by_hash.remove(hash) {
+            if let Some(hashes) = self.by_level.get_mut(&level) {
+                hashes.retain(|h| h != hash);
+                if hashes.is_empty() {
+                    self.by_level.remove(&level);
+                }
+            }
+        }
+        if self.tip.as_ref() == Some(hash) {
+            self.tip = None;
+        }
+    }
+
+    /// Appends operations of one validation pass of the block,
+    /// returns false if they are already stored
+    pub fn append_operations(
+        &mut self,
+        operations: &OperationsForBlocksMessage,
+    ) -> Result<bool, StorageError> {
+        let block = operations.operations_for_block();
+        let key = (block.hash().clone(), *block.validation_pass());
+        if self.operations.contains_key(&key) {
+            return Ok(false);
+        }
+        let bytes = operations.as_bytes().map_err(|_| StorageError::Encoding)?;
+        let mut record = Vec::with_capacity(5 + HASH_SIZE + bytes.len());
+        record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        record.extend_from_slice(key.0.as_ref());
+        record.push(key.1 as u8);
+        record.extend_from_slice(bytes.as_ref());
+        self.operations_file
+            .write_all(&record)
+            .map_err(StorageError::Io)?;
+
+        self.index_operations(operations, self.operations_end)?;
+        self.operations_end += record.len() as u64;
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.flush()?;
+        }
+        Ok(true)
+    }
+
+    pub fn contains_operations(&self, hash: &Hash, validation_pass: i8) -> bool {
+        self.operations
+            .contains_key(&(hash.clone(), validation_pass))
+    }
+
+    pub fn operations(
+        &self,
+        hash: &Hash,
+        validation_pass: i8,
+    ) -> Result<Option<OperationsForBlocksMessage>, StorageError> {
+        let offset = match self.operations.get(&(hash.clone(), validation_pass)) {
+            Some(&offset) => offset,
+            None => return Ok(None),
+        };
+        let mut length = [0; 4];
+        self.operations_file
+            .read_exact_at(&mut length, offset)
+            .map_err(StorageError::Io)?;
+        let mut bytes = vec![0; u32::from_be_bytes(length) as usize];
+        self.operations_file
+            .read_exact_at(&mut bytes, offset + (5 + HASH_SIZE) as u64)
+            .map_err(StorageError::Io)?;
+        OperationsForBlocksMessage::from_bytes(bytes)
+            .map(Some)
+            .map_err(|_| StorageError::Decoding)
+    }
+
+    /// The operation by its hash, from the stored operations of any block
+    pub fn operation(&self, hash: &Hash) -> Result<Option<Operation>, StorageError> {
+        let (block, validation_pass, index) = match self.by_operation.get(hash) {
+            Some(&(ref block, validation_pass, index)) => (block, validation_pass, index),
+            None => return Ok(None),
+        };
+        Ok(self
+            .operations(block, validation_pass)?
+            .and_then(|operations| operations.operations().get(index).cloned()))
+    }
+
+    /// Stores the protocol, the hash must be checked by the caller
+    pub fn append_protocol(
+        &mut self,
+        hash: &Hash,
+        protocol: &Protocol,
+    ) -> Result<(), StorageError> {
+        let bytes = protocol.as_bytes().map_err(|_| StorageError::Encoding)?;
+        self.replace(&protocol_file(hash), &bytes)
+    }
+
+    pub fn contains_protocol(&self, hash: &Hash) -> bool {
+        self.path.join(protocol_file(hash)).is_file()
+    }
+
+    pub fn protocol(&self, hash: &Hash) -> Result<Option<Protocol>, StorageError> {
+        match fs::read(self.path.join(protocol_file(hash))) {
+            Ok(bytes) => Protocol::from_bytes(bytes)
+                .map(Some)
+                .map_err(|_| StorageError::Decoding),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(StorageError::Io(error)),
+        }
+    }
+
+    /// Forces appended headers and operations to the disk
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        if self.unsynced > 0 {
+            self.file.sync_data().map_err(StorageError::Io)?;
+            self.operations_file.sync_data().map_err(StorageError::Io)?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, hash: &Hash) -> Result<Option<BlockHeader>, StorageError> {
+        match self.by_hash.get(hash) {
+            Some(&(offset, _)) => self.read(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn level(&self, hash: &Hash) -> Option<i32> {
+        self.by_hash.get(hash).map(|&(_, level)| level)
+    }
+
+    /// Hashes of the stored blocks at the level, there are several if the chain has forks
+    pub fn hashes_at(&self, level: i32) -> &[Hash] {
+        self.by_level.get(&level).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Headers of the levels in the range, in ascending order
+    pub fn range<R>(
+        &self,
+        levels: R,
+    ) -> impl Iterator<Item = Result<BlockHeader, StorageError>> + '_
+    where
+        R: RangeBounds<i32>,
+    {
+        self.by_level
+            .range(levels)
+            .flat_map(|(_, hashes)| hashes.iter())
+            .map(move |hash| self.read(self.by_hash[hash].0))
+    }
+
+    pub fn tip(&self) -> Option<&Hash> {
+        self.tip.as_ref()
+    }
+
+    /// Moves the tip, the tip never points to a header which is not on the disk
+    pub fn set_tip(&mut self, hash: &Hash) -> Result<(), StorageError> {
+        if !self.contains(hash) {
+            return Err(StorageError::UnknownBlock);
+        }
+        self.flush()?;
+        self.replace(TIP_FILE, hash.as_ref())?;
+        self.tip = Some(hash.clone());
+        Ok(())
+    }
+
+    /// The progress saved by the interrupted sync, if any,
+    /// none if the checkpoint refers to a header which is not stored
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        let data = fs::read(self.path.join(CHECKPOINT_FILE)).ok()?;
+        let mut entries = data.chunks_exact(HASH_SIZE + 4).map(|entry| {
+            let (hash, level) = entry.split_at(HASH_SIZE);
+            (hash.to_vec(), u32::from_be_bytes(<[u8; 4]>::try_from(level).unwrap()))
+        });
+        let (head, _) = entries.next()?;
+        if !self.contains(&head) {
+            return None;
+        }
+        Some(Checkpoint {
+            head: head,
+            anchors: entries.collect(),
+        })
+    }
+
+    /// Saves the progress, appended headers are flushed first,
+    /// so the checkpoint never refers to a header which is not on the disk
+    pub fn save_checkpoint(&mut self, checkpoint: &Checkpoint) -> Result<(), StorageError> {
+        let level = self
+            .level(&checkpoint.head)
+            .ok_or(StorageError::UnknownBlock)?;
+        self.flush()?;
+        let mut data = Vec::with_capacity((checkpoint.anchors.len() + 1) * (HASH_SIZE + 4));
+        let head = (&checkpoint.head, level as u32);
+        let anchors = checkpoint.anchors.iter().map(|(hash, level)| (hash, *level));
+        for (hash, level) in Some(head).into_iter().chain(anchors) {
+            data.extend_from_slice(hash.as_ref());
+            data.extend_from_slice(&level.to_be_bytes());
+        }
+        self.replace(CHECKPOINT_FILE, &data)
+    }
+
+    /// The sync is finished, nothing to resume
+    pub fn clear_checkpoint(&mut self) -> Result<(), StorageError> {
+        match fs::remove_file(self.path.join(CHECKPOINT_FILE)) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(StorageError::Io(error)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the file atomically, readers see either the old content or the new one
+    fn replace(&self, name: &str, data: &[u8]) -> Result<(), StorageError> {
+        let temporary = self.path.join(format!("{}.tmp", name));
+        let mut file = File::create(&temporary).map_err(StorageError::Io)?;
+        file.write_all(data).map_err(StorageError::Io)?;
+        file.sync_all().map_err(StorageError::Io)?;
+        fs::rename(&temporary, self.path.join(name)).map_err(StorageError::Io)?;
+        // the rename is durable once the directory is synced
+        File::open(&self.path)
+            .and_then(|directory| directory.sync_all())
+            .map_err(StorageError::Io)
+    }
+
+    fn index(&mut self, hash: Hash, offset: u64, level: i32) {
+        // the forgotten header might be appended again
+        let hashes = self.by_level.entry(level).or_default();
+        if !hashes.contains(&hash) {
+            hashes.push(hash.clone());
+        }
+        self.by_hash.insert(hash, (offset, level));
+    }
+
+    fn index_operations(
+        &mut self,
+        operations: &OperationsForBlocksMessage,
+        offset: u64,
+    ) -> Result<(), StorageError> {
+        let block = operations.operations_for_block();
+        let key = (block.hash().clone(), *block.validation_pass());
+        for (index, operation) in operations.operations().iter().enumerate() {
+            let hash = merkle::operation_hash(operation).map_err(|_| StorageError::Encoding)?;
+            self.by_operation
+                .insert(hash, (key.0.clone(), key.1, index));
+        }
+        self.operations.insert(key, offset);
+        Ok(())
+    }
+
+    fn read(&self, offset: u64) -> Result<BlockHeader, StorageError> {
+        let mut length = [0; 4];
+        self.file
+            .read_exact_at(&mut length, offset)
+            .map_err(StorageError::Io)?;
+        let mut bytes = vec![0; u32::from_be_bytes(length) as usize];
+        self.file
+            .read_exact_at(&mut bytes, offset + (4 + HASH_SIZE) as u64)
+            .map_err(StorageError::Io)?;
+        BlockHeader::from_bytes(bytes).map_err(|_| StorageError::Decoding)
+    }
+}
+
+fn protocol_file(hash: &Hash) -> String {
+    format!("{}/{}", PROTOCOLS_DIRECTORY, hex::encode(hash))
+}
+
+fn open_data(path: &Path) -> Result<File, StorageError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .map_err(StorageError::Io)
+}
+
+/// Reads the records one by one, the `decode` gets the offset of the record, the fixed part
+/// after the length and the payload, and tells if the record is good,
+/// returns the end of the last good record
+fn scan<F>(file: &File, fixed: usize, mut decode: F) -> Result<u64, StorageError>
+where
+    F: FnMut(u64, &[u8], Vec<u8>) -> Result<bool, StorageError>,
+{
+    let size = file.metadata().map_err(StorageError::Io)?.len();
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(0)).map_err(StorageError::Io)?;
+    let mut prefix = vec![0; 4 + fixed];
+    let (mut offset, mut end) = (0, 0);
+    while offset + prefix.len() as u64 <= size {
+        reader.read_exact(&mut prefix).map_err(StorageError::Io)?;
+        let length = u32::from_be_bytes(<[u8; 4]>::try_from(&prefix[..4]).unwrap()) as u64;
+        let next = offset + prefix.len() as u64 + length;
+        if next > size {
+            // the process died in the middle of the write
+            break;
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload).map_err(StorageError::Io)?;
+        if decode(offset, &prefix[4..], payload)? {
+            end = next;
+        }
+        offset = next;
+    }
+    Ok(end)
+}
+
+/// Cuts away whatever follows the last good record at the `end`, returns the new end of the file
+fn cut(file: &mut File, end: u64) -> Result<u64, StorageError> {
+    let length = file.metadata().map_err(StorageError::Io)?.len();
+    if end < length {
+        file.set_len(end).map_err(StorageError::Io)?;
+        file.sync_all().map_err(StorageError::Io)?;
+    }
+    file.seek(SeekFrom::Start(end)).map_err(StorageError::Io)
+}
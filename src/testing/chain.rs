@@ -0,0 +1,102 @@
+use tezos_messages::p2p::encoding::block_header::{BlockHeader, BlockHeaderBuilder, Fitness};
+use super::super::bootstrap::{genesis, block_hash, merkle};
+
+/// Validation passes of generated blocks, each pass has no operations
+pub const VALIDATION_PASSES: u8 = 4;
+
+/// Builds linked chains of block headers starting at the genesis
+#[derive(Clone)]
+pub struct ChainGenerator {
+    length: usize,
+    interval: i64,
+    fitness: fn(i32) -> Fitness,
+    seed: u8,
+    protocol_change: Option<i32>,
+}
+
+/// Emmy-like fitness: version and the big-endian level
+pub fn default_fitness(level: i32) -> Fitness {
+    vec![vec![1], (level as u64).to_be_bytes().to_vec()]
+}
+
+impl ChainGenerator {
+    /// The `length` includes the genesis
+    pub fn new(length: usize) -> Self {
+        ChainGenerator {
+            length: length,
+            interval: 60,
+            fitness: default_fitness,
+            seed: 0,
+            protocol_change: None,
+        }
+    }
+
+    /// Seconds between consecutive blocks
+    pub fn interval(mut self, interval: i64) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn fitness(mut self, fitness: fn(i32) -> Fitness) -> Self {
+        self.fitness = fitness;
+        self
+    }
+
+    /// Different seeds give different hashes for the same levels
+    pub fn seed(mut self, seed: u8) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Blocks from the `level` up belong to the next protocol
+    pub fn protocol_change(mut self, level: i32) -> Self {
+        self.protocol_change = Some(level);
+        self
+    }
+
+    pub fn generate(&self) -> Vec<BlockHeader> {
+        let mut headers = vec![genesis::block_header()];
+        self.extend(&mut headers, self.length);
+        headers
+    }
+
+    /// Takes `base` up to the `level` and grows another branch of `length` blocks on it,
+    /// the fork gets the seed of this generator, so it should differ from the seed of `base`
+    pub fn fork(&self, base: &[BlockHeader], level: i32, length: usize) -> Vec<BlockHeader> {
+        let mut headers = base
+            .iter()
+            .take_while(|header| header.level() <= level)
+            .cloned()
+            .collect::<Vec<_>>();
+        let target = headers.len() + length;
+        self.extend(&mut headers, target);
+        headers
+    }
+
+    fn extend(&self, headers: &mut Vec<BlockHeader>, length: usize) {
+        let empty = merkle::root(&[]);
+        let passes = vec![empty; VALIDATION_PASSES as usize];
+        let operations_hash = merkle::operations_hash(&passes);
+        while headers.len() < length {
+            let predecessor = headers.last().unwrap();
+            let level = predecessor.level() + 1;
+            let proto = match self.protocol_change {
+                Some(change) if level >= change => 2,
+                _ => 1,
+            };
+            let header = BlockHeaderBuilder::default()
+                .level(level)
+                .proto(proto)
+                .predecessor(block_hash(predecessor).unwrap())
+                .timestamp(predecessor.timestamp() + self.interval)
+                .validation_pass(VALIDATION_PASSES)
+                .operations_hash(operations_hash.clone())
+                .fitness((self.fitness)(level))
+                .context(vec![0; 32])
+                .protocol_data(vec![self.seed])
+                .build()
+                .unwrap();
+            headers.push(header);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Helpers for end-to-end tests without a real Tezos node
+
+mod peer;
+pub use self::peer::{MockPeer, Fault};
+
+mod chain;
+pub use self::chain::{ChainGenerator, VALIDATION_PASSES, default_fitness};
+
+use slog::Logger;
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{block_header::BlockHeader, operation::Operation, protocol::Protocol},
+};
+use crypto::hash::Hash;
+use super::bootstrap::{protocol_hash, merkle};
+
+pub fn logger() -> Logger {
+    Logger::root(slog::Discard, slog::o!())
+}
+
+/// Protocol with a single component, the `source` is its implementation
+pub fn protocol(source: &str) -> (Hash, Protocol) {
+    fn string(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend_from_slice(&(s.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(s.as_bytes());
+    }
+
+    let mut component = Vec::new();
+    string(&mut component, "main");
+    // no interface
+    component.push(0);
+    string(&mut component, source);
+    // the environment version and the list of components
+    let mut bytes = vec![0, 0];
+    bytes.extend_from_slice(&(component.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&component);
+
+    let protocol = Protocol::from_bytes(bytes).unwrap();
+    (protocol_hash(&protocol).unwrap(), protocol)
+}
+
+/// Operation on the `branch` whose content is the `data`
+pub fn operation(branch: &Hash, data: &[u8]) -> (Hash, Operation) {
+    let mut bytes = branch.clone();
+    bytes.extend_from_slice(data);
+    let operation = Operation::from_bytes(bytes).unwrap();
+    (merkle::operation_hash(&operation).unwrap(), operation)
+}
+
+/// Linked headers starting at the genesis with default parameters, the genesis goes first
+pub fn chain(length: usize) -> Vec<BlockHeader> {
+    ChainGenerator::new(length).generate()
+}
@@ -0,0 +1,333 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    io::AsyncWriteExt,
+    task::JoinHandle,
+};
+use tezos_conversation::Identity;
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        prelude::*,
+        ack::AckMessage,
+        metadata::MetadataMessage,
+        operation::Operation,
+        protocol::Protocol,
+        peer::{PeerMessage, PeerMessageResponse},
+    },
+};
+use crypto::hash::Hash;
+use super::super::{
+    error::SocketError,
+    handshake_state::incoming_connection,
+    decipher_state::{DecipherState, CONTENT_LENGTH_MAX},
+    read_message_state::ReadMessageState,
+    bootstrap::{ChainId, MAX_REQUEST_LENGTH, genesis, block_hash, merkle},
+};
+
+const IDENTITY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testing/identity.json");
+
+/// Misbehavior of the mock peer
+#[derive(Clone)]
+pub enum Fault {
+    /// refuse the connection at the acknowledge step
+    Nack,
+    /// answer `GetCurrentBranch` with another chain id
+    WrongChainId,
+    /// send only a half of the n-th block header and close the connection
+    TruncatedChunk(usize),
+    /// corrupt the authentication tag of the n-th block header
+    BadMac(usize),
+    /// wait before sending the n-th block header
+    Stall(usize, Duration),
+    /// send the head instead of the n-th requested block header
+    WrongHeader(usize),
+    /// add an operation which is not in the block to every validation pass
+    WrongOperations,
+    /// never answer `GetOperationsForBlocks`, like a node which pruned old operations
+    IgnoreOperations,
+}
+
+/// Responder which serves a synthetic chain to a single connection
+pub struct MockPeer {
+    identity: String,
+    chain_id: ChainId,
+    headers: HashMap<Hash, BlockHeader>,
+    /// the head of the current branch
+    head: BlockHeader,
+    history: Vec<Hash>,
+    /// the answer to `GetCurrentHead`
+    current_head: BlockHeader,
+    protocols: HashMap<Hash, Protocol>,
+    /// announced in `CurrentHead`
+    mempool: HashMap<Hash, Operation>,
+    private_node: bool,
+    faults: Vec<Fault>,
+}
+
+impl MockPeer {
+    /// The `headers` must go from the genesis to the head
+    pub fn new(headers: Vec<BlockHeader>) -> Self {
+        let head = headers.last().cloned().unwrap_or_else(genesis::block_header);
+        // hashes of blocks 1, 2, 4, 8, ... levels below the head
+        let history = (0..)
+            .map(|i| 1usize << i)
+            .take_while(|&distance| distance < headers.len())
+            .map(|distance| block_hash(&headers[headers.len() - 1 - distance]).unwrap())
+            .collect();
+        MockPeer {
+            identity: IDENTITY_PATH.to_string(),
+            chain_id: genesis::CHAIN_ID,
+            headers: headers
+                .into_iter()
+                .map(|header| (block_hash(&header).unwrap(), header))
+                .collect(),
+            current_head: head.clone(),
+            head: head,
+            history: history,
+            protocols: HashMap::new(),
+            mempool: HashMap::new(),
+            private_node: false,
+            faults: Vec::new(),
+        }
+    }
+
+    /// Reports the block at the `level` as the head of the current branch,
+    /// the last block is reported only as the current head
+    pub fn branch_head(mut self, level: i32) -> Self {
+        let head = self
+            .headers
+            .values()
+            .find(|header| header.level() == level)
+            .unwrap()
+            .clone();
+        let mut ancestors = Vec::new();
+        let mut current = &head;
+        while current.level() > 0 {
+            ancestors.push(current.predecessor().clone());
+            current = &self.headers[current.predecessor()];
+        }
+        self.history = (0..)
+            .map(|i| 1usize << i)
+            .take_while(|&distance| distance <= ancestors.len())
+            .map(|distance| ancestors[distance - 1].clone())
+            .collect();
+        self.head = head;
+        self
+    }
+
+    pub fn history(mut self, history: Vec<Hash>) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn protocol(mut self, (hash, protocol): (Hash, Protocol)) -> Self {
+        self.protocols.insert(hash, protocol);
+        self
+    }
+
+    pub fn mempool_operation(mut self, (hash, operation): (Hash, Operation)) -> Self {
+        self.mempool.insert(hash, operation);
+        self
+    }
+
+    /// Asks not to be advertised in the metadata
+    pub fn private(mut self) -> Self {
+        self.private_node = true;
+        self
+    }
+
+    pub fn fault(mut self, fault: Fault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// Listens on a localhost port, accepts one connection and serves it in background
+    pub async fn spawn(
+        self,
+    ) -> Result<(SocketAddr, JoinHandle<Result<(), SocketError>>), SocketError> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(SocketError::Io)?;
+        let address = listener.local_addr().map_err(SocketError::Io)?;
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.map_err(SocketError::Io)?;
+            self.serve(stream).await
+        });
+        Ok((address, handle))
+    }
+
+    async fn serve(self, mut stream: TcpStream) -> Result<(), SocketError> {
+        let identity = Identity::from_path(self.identity.clone()).unwrap();
+        let mut decipher = incoming_connection(&mut stream, &identity).await?;
+
+        let _ = decipher.read_chunk(&mut stream).await?;
+        let m = MetadataMessage::new(false, self.private_node);
+        decipher.write_message(&mut stream, &[m]).await?;
+
+        let _ = decipher.read_chunk(&mut stream).await?;
+        if self.faults.iter().any(|f| matches!(f, Fault::Nack)) {
+            return decipher.write_message(&mut stream, &[AckMessage::NackV0]).await;
+        }
+        decipher.write_message(&mut stream, &[AckMessage::Ack]).await?;
+
+        let logger = super::logger();
+        let mut reader = ReadMessageState::<PeerMessageResponse>::new();
+        let mut sent_headers = 0;
+        loop {
+            let message = match reader.read_message(&logger, &mut stream, &mut decipher).await {
+                Ok(message) => message,
+                // the peer has closed the connection
+                Err(SocketError::Io(_)) => break Ok(()),
+                Err(error) => break Err(error),
+            };
+            for message in message.messages() {
+                // a real node cannot decode longer lists and drops the connection
+                let length = match message {
+                    &PeerMessage::GetBlockHeaders(ref m) => m.get_block_headers().len(),
+                    &PeerMessage::GetOperationsForBlocks(ref m) => {
+                        m.get_operations_for_blocks().len()
+                    },
+                    &PeerMessage::GetOperations(ref m) => m.get_operations().len(),
+                    &PeerMessage::GetProtocols(ref m) => m.get_protocols().len(),
+                    _ => 0,
+                };
+                if length > MAX_REQUEST_LENGTH {
+                    return Err(SocketError::DecodingError);
+                }
+                match message {
+                    &PeerMessage::GetCurrentBranch(_) => {
+                        let mut chain_id = self.chain_id;
+                        if self.faults.iter().any(|f| matches!(f, Fault::WrongChainId)) {
+                            chain_id[0] ^= 0xff;
+                        }
+                        let branch = CurrentBranch::new(self.head.clone(), self.history.clone());
+                        let response = CurrentBranchMessage::new(chain_id.to_vec(), branch);
+                        self.send(&mut stream, &mut decipher, response.into(), None)
+                            .await?;
+                    },
+                    &PeerMessage::GetBlockHeaders(ref m) => {
+                        for hash in m.get_block_headers() {
+                            if let Some(header) = self.headers.get(hash) {
+                                let wrong = self.faults.iter().any(|f| match f {
+                                    &Fault::WrongHeader(n) => n == sent_headers,
+                                    _ => false,
+                                });
+                                let header = if wrong { &self.head } else { header };
+                                let response = BlockHeaderMessage::from(header.clone()).into();
+                                let index = Some(sent_headers);
+                                let open = self
+                                    .send(&mut stream, &mut decipher, response, index)
+                                    .await?;
+                                if !open {
+                                    return Ok(());
+                                }
+                                sent_headers += 1;
+                            }
+                        }
+                    },
+                    &PeerMessage::GetOperationsForBlocks(_)
+                        if self.faults.iter().any(|f| matches!(f, Fault::IgnoreOperations)) => {},
+                    &PeerMessage::GetOperationsForBlocks(ref m) => {
+                        for block in m.get_operations_for_blocks() {
+                            if let Some(header) = self.headers.get(block.hash()) {
+                                // every pass is empty
+                                let passes = header.validation_pass() as usize;
+                                let leaves = vec![merkle::root(&[]); passes];
+                                let path =
+                                    merkle::path(&leaves, *block.validation_pass() as usize);
+                                let operations = if self
+                                    .faults
+                                    .iter()
+                                    .any(|f| matches!(f, Fault::WrongOperations))
+                                {
+                                    let mut bytes = block.hash().clone();
+                                    bytes.extend_from_slice(b"operation");
+                                    vec![Operation::from_bytes(bytes).unwrap()]
+                                } else {
+                                    vec![]
+                                };
+                                let response = OperationsForBlocksMessage::new(
+                                    block.clone(),
+                                    path,
+                                    operations,
+                                );
+                                self.send(&mut stream, &mut decipher, response.into(), None)
+                                    .await?;
+                            }
+                        }
+                    },
+                    &PeerMessage::GetCurrentHead(_) => {
+                        let known_valid = self.mempool.keys().cloned().collect();
+                        let mempool = Mempool::new(known_valid, Vec::new());
+                        let response = CurrentHeadMessage::new(
+                            self.chain_id.to_vec(),
+                            self.current_head.clone(),
+                            mempool,
+                        );
+                        self.send(&mut stream, &mut decipher, response.into(), None)
+                            .await?;
+                    },
+                    &PeerMessage::GetOperations(ref m) => {
+                        for hash in m.get_operations() {
+                            if let Some(operation) = self.mempool.get(hash) {
+                                let response = OperationMessage::from(operation.clone());
+                                self.send(&mut stream, &mut decipher, response.into(), None)
+                                    .await?;
+                            }
+                        }
+                    },
+                    &PeerMessage::GetProtocols(ref m) => {
+                        for hash in m.get_protocols() {
+                            if let Some(protocol) = self.protocols.get(hash) {
+                                let response = ProtocolMessage::new(protocol.clone());
+                                self.send(&mut stream, &mut decipher, response.into(), None)
+                                    .await?;
+                            }
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Returns false if the connection should be closed
+    async fn send(
+        &self,
+        stream: &mut TcpStream,
+        decipher: &mut DecipherState,
+        message: PeerMessageResponse,
+        header_index: Option<usize>,
+    ) -> Result<bool, SocketError> {
+        for fault in &self.faults {
+            match fault {
+                &Fault::Stall(n, duration) if Some(n) == header_index => {
+                    tokio::time::sleep(duration).await
+                },
+                _ => (),
+            }
+        }
+
+        let bytes = message.as_bytes().map_err(|_| SocketError::EncodingError)?;
+        for plain in bytes.chunks(CONTENT_LENGTH_MAX) {
+            let mut raw = decipher.encrypt(plain)?.raw().to_vec();
+            for fault in &self.faults {
+                match fault {
+                    &Fault::TruncatedChunk(n) if Some(n) == header_index => {
+                        let half = raw.len() / 2;
+                        stream.write_all(&raw[..half]).await.map_err(SocketError::Io)?;
+                        return Ok(false);
+                    },
+                    &Fault::BadMac(n) if Some(n) == header_index => {
+                        // the tag goes right after the size prefix
+                        raw[2] ^= 0xff;
+                    },
+                    _ => (),
+                }
+            }
+            stream.write_all(&raw).await.map_err(SocketError::Io)?;
+        }
+        Ok(true)
+    }
+}
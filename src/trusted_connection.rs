@@ -1,7 +1,7 @@
 use std::{fmt, slice};
 use tokio::net::TcpStream;
 use slog::Logger;
-use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::{binary_message::BinaryMessage, encoding::metadata::MetadataMessage};
 use super::{error::SocketError, decipher_state::DecipherState, read_message_state::ReadMessageState};
 
 pub struct TrustedConnection<M>
@@ -11,6 +11,8 @@ where
     reader: ReadMessageState<M>,
     decipher: DecipherState,
     stream: TcpStream,
+    /// received from the peer during the handshake
+    metadata: MetadataMessage,
     logger: Logger,
 }
 
@@ -18,15 +20,26 @@ impl<M> TrustedConnection<M>
 where
     M: BinaryMessage + fmt::Debug,
 {
-    pub fn new(stream: TcpStream, decipher: DecipherState, logger: &Logger) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        decipher: DecipherState,
+        metadata: MetadataMessage,
+        logger: &Logger,
+    ) -> Self {
         TrustedConnection {
             reader: ReadMessageState::new(),
             decipher: decipher,
             stream: stream,
+            metadata: metadata,
             logger: logger.clone(),
         }
     }
 
+    /// The metadata of the peer
+    pub fn metadata(&self) -> &MetadataMessage {
+        &self.metadata
+    }
+
     #[allow(dead_code)]
     pub fn transmute<Mx>(self) -> TrustedConnection<Mx>
     where
@@ -36,6 +49,7 @@ where
             reader: ReadMessageState::new(),
             decipher: self.decipher,
             stream: self.stream,
+            metadata: self.metadata,
             logger: self.logger,
         }
     }
@@ -45,6 +59,7 @@ where
             ref mut reader,
             ref mut decipher,
             ref mut stream,
+            metadata: _,
             ref logger,
         } = self;
         reader.read_message(logger, stream, decipher).await
@@ -65,6 +80,7 @@ where
             reader: _,
             ref mut decipher,
             ref mut stream,
+            metadata: _,
             ref logger,
         } = self;
         slog::debug!(logger, "-> {:x?}", messages);
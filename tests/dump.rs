@@ -0,0 +1,99 @@
+use tezos_messages::p2p::encoding::block_header::BlockHeader;
+use tezedge_bootstrap_poc::{
+    block_hash, dump,
+    testing::{self, ChainGenerator},
+};
+
+fn hash(header: &BlockHeader) -> String {
+    hex::encode(block_hash(header).unwrap())
+}
+
+#[test]
+fn linkage() {
+    let headers = testing::chain(6);
+    let summary = dump::Summary::new(&headers).unwrap();
+    assert!(summary.is_linked());
+    assert_eq!(summary.count, 6);
+    assert_eq!(summary.levels, Some((0, 5)));
+    assert_eq!(summary.first, Some(hash(&headers[0])));
+    assert_eq!(summary.last, Some(hash(&headers[5])));
+
+    // the dump written by the sync goes from the head
+    let reversed = headers.iter().rev().cloned().collect::<Vec<_>>();
+    let summary = dump::Summary::new(&reversed).unwrap();
+    assert!(summary.is_linked());
+    assert_eq!(summary.levels, Some((0, 5)));
+    assert_eq!(summary.first, Some(hash(&headers[5])));
+    assert_eq!(summary.last, Some(hash(&headers[0])));
+}
+
+#[test]
+fn breaks() {
+    let headers = testing::chain(6);
+    // a missing header
+    let mut missing = headers.clone();
+    missing.remove(3);
+    assert_eq!(dump::Summary::new(&missing).unwrap().breaks, vec![4]);
+    missing.reverse();
+    assert_eq!(dump::Summary::new(&missing).unwrap().breaks, vec![4]);
+
+    // a header of another branch at the same level
+    let fork = ChainGenerator::new(0).seed(1).fork(&headers, 2, 2);
+    let mut spliced = headers.clone();
+    spliced[3] = fork[3].clone();
+    assert_eq!(dump::Summary::new(&spliced).unwrap().breaks, vec![4]);
+    spliced.reverse();
+    assert_eq!(dump::Summary::new(&spliced).unwrap().breaks, vec![4]);
+}
+
+#[test]
+fn summary_format() {
+    let headers = testing::chain(2);
+    let expected = format!(
+        "headers: 2\nlevels: 0..=1\nfirst: {}\nlast: {}\nlinkage: ok",
+        hash(&headers[0]),
+        hash(&headers[1]),
+    );
+    assert_eq!(dump::Summary::new(&headers).unwrap().to_string(), expected);
+
+    let broken = vec![headers[0].clone(), testing::chain(3)[2].clone()];
+    let summary = dump::Summary::new(&broken).unwrap().to_string();
+    assert!(summary.ends_with("linkage: broken at levels [2]"));
+    assert_eq!(dump::Summary::new(&[]).unwrap().to_string(), "headers: 0\nlinkage: ok");
+}
+
+#[test]
+fn json() {
+    let headers = testing::chain(3);
+    let mut output = Vec::new();
+    dump::write_json(&mut output, &headers).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    let row = serde_json::from_str::<serde_json::Value>(lines[2]).unwrap();
+    assert_eq!(row["level"], 2);
+    assert_eq!(row["hash"], hash(&headers[2]));
+    assert_eq!(row["predecessor"], hash(&headers[1]));
+    assert_eq!(row["validation_pass"], testing::VALIDATION_PASSES);
+    assert_eq!(row["fitness"], serde_json::json!(["01", "0000000000000002"]));
+}
+
+#[test]
+fn csv() {
+    let headers = testing::chain(3);
+    let mut output = Vec::new();
+    dump::write_csv(&mut output, &headers).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[0],
+        "level,hash,predecessor,timestamp,proto,validation_pass,operations_hash,fitness,context",
+    );
+    assert_eq!(lines.len(), 4);
+    let fields = lines[3].split(',').collect::<Vec<_>>();
+    assert_eq!(fields.len(), 9);
+    assert_eq!(fields[0], "2");
+    assert_eq!(fields[1], hash(&headers[2]));
+    assert_eq!(fields[3], headers[2].timestamp().to_string());
+    assert_eq!(fields[7], "01:0000000000000002");
+}
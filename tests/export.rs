@@ -0,0 +1,110 @@
+use std::io::Cursor;
+use tezos_messages::p2p::encoding::block_header::BlockHeader;
+use tezedge_bootstrap_poc::{
+    ExportError, block_hash, read_dump, write_dump,
+    export::{self, ExportWriter, ExportReader, read_index},
+    testing,
+};
+
+fn export(length: usize) -> Vec<u8> {
+    let mut writer = ExportWriter::new(Vec::new()).unwrap();
+    for header in &testing::chain(length) {
+        writer.push(header).unwrap();
+    }
+    writer.finish().unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let headers = testing::chain(2500);
+    let data = export(2500);
+    let read = ExportReader::new(Cursor::new(&data))
+        .unwrap()
+        .map(|header| block_hash(&header.unwrap()).unwrap())
+        .collect::<Vec<_>>();
+    let expected = headers
+        .iter()
+        .map(|header| block_hash(header).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(read, expected);
+
+    let index = read_index(Cursor::new(&data)).unwrap();
+    let levels = index.iter().map(|entry| entry.level).collect::<Vec<_>>();
+    let counts = index.iter().map(|entry| entry.count).collect::<Vec<_>>();
+    assert_eq!(levels, vec![0, 1024, 2048]);
+    assert_eq!(counts, vec![1024, 1024, 452]);
+}
+
+#[test]
+fn convert_dump() {
+    let headers = testing::chain(40);
+    let hashes = |headers: &[BlockHeader]| {
+        headers
+            .iter()
+            .map(|header| block_hash(header).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let path = std::env::temp_dir().join(format!("convert-{}", std::process::id()));
+    std::fs::create_dir_all(&path).unwrap();
+    let (dump, target) = (path.join("data.dump"), path.join("data.export"));
+
+    // the dump goes from the head
+    let mut reversed = headers.clone();
+    reversed.reverse();
+    write_dump(&dump, reversed.clone()).unwrap();
+    assert_eq!(hashes(&read_dump(&dump).unwrap()), hashes(&reversed));
+    assert_eq!(export::convert_dump(&dump, &target).unwrap(), 40);
+
+    let converted = export::open(&target)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(hashes(&converted), hashes(&headers));
+
+    // either file is recognized by its content
+    assert_eq!(hashes(&export::read_headers(&dump).unwrap()), hashes(&reversed));
+    assert_eq!(hashes(&export::read_headers(&target).unwrap()), hashes(&headers));
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn corrupted_record() {
+    let mut data = export(8);
+    // the last byte of the first record
+    let length = u32::from_be_bytes([data[11], data[12], data[13], data[14]]) as usize;
+    data[14 + length] ^= 0xff;
+    let result = ExportReader::new(Cursor::new(&data))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+    assert!(result.is_err());
+}
+
+#[test]
+fn truncated() {
+    let data = export(8);
+    let last = ExportReader::new(Cursor::new(&data[..data.len() / 2]))
+        .unwrap()
+        .last()
+        .unwrap();
+    assert!(matches!(last, Err(ExportError::Truncated)));
+}
+
+#[test]
+fn frame_too_large() {
+    // the magic and the version followed by a record of 4 GiB
+    let mut data = export(0)[..10].to_vec();
+    data.extend_from_slice(&[1, 0xff, 0xff, 0xff, 0xff]);
+    let first = ExportReader::new(Cursor::new(&data))
+        .unwrap()
+        .next()
+        .unwrap();
+    assert!(matches!(first, Err(ExportError::FrameTooLarge(0xffff_ffff))));
+
+    // the trailer points to an index of 4 GiB
+    let offset = data.len() as u64;
+    data.extend_from_slice(&[3, 0xff, 0xff, 0xff, 0xff]);
+    data.extend_from_slice(&offset.to_be_bytes());
+    data.extend_from_slice(b"TZCHAIN\0");
+    let index = read_index(Cursor::new(&data));
+    assert!(matches!(index, Err(ExportError::FrameTooLarge(0xffff_ffff))));
+}
@@ -0,0 +1,205 @@
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        block_header::{BlockHeader, BlockHeaderBuilder},
+        operation::Operation,
+        operations_for_blocks::{OperationsForBlock, OperationsForBlocksMessage},
+        operation_hashes_for_blocks::{OperationHashesForBlock, OperationHashesForBlocksMessage},
+    },
+};
+use crypto::hash::Hash;
+use tezedge_bootstrap_poc::{SharedChain, block_hash, merkle, testing};
+
+/// Operations hash of mainnet blocks with four empty validation passes,
+/// LLoa7bxRTKaQN2bLYoitYB6bU2DvLnBAqrVjZcvJ364cTcX2PZYKU
+const FOUR_EMPTY_PASSES: &str = "683625c2445a4e9564bf710c5528fd99a7d150d2a2a323bc22ff9e2710da4f6d";
+
+#[test]
+fn empty_root() {
+    // the operations hash of the genesis
+    let expected =
+        hex::decode("0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8").unwrap();
+    assert_eq!(merkle::root(&[]), expected);
+}
+
+#[test]
+fn path() {
+    for length in 1..10 {
+        let leaves = (0..length).map(|i| vec![i as u8; 32]).collect::<Vec<_>>();
+        let root = merkle::root(&leaves);
+        for (index, leaf) in leaves.iter().enumerate() {
+            let path = merkle::path(&leaves, index);
+            let expected = (root.clone(), length.next_power_of_two(), index);
+            assert_eq!(merkle::check_path(&path, leaf), expected);
+        }
+    }
+}
+
+#[test]
+fn empty_passes() {
+    // operations hash of mainnet blocks with one empty validation pass,
+    // LLoaGLRPRx3Zf8kB4ACtgku8F4feeBiskeb41J1ciwfcXB3KzHKXc
+    let one =
+        hex::decode("7c09f7c4d76ace86e1a7e1c7dc0a0c7edcaa8b284949320081131976a87760c3").unwrap();
+    let four = hex::decode(FOUR_EMPTY_PASSES).unwrap();
+    let empty = merkle::root(&[]);
+    assert_eq!(merkle::operations_hash(&[empty.clone()]), one);
+    assert_eq!(merkle::operations_hash(&vec![empty; 4]), four);
+}
+
+#[test]
+fn single_leaf_is_hashed() {
+    let leaf = vec![7; 32];
+    let root = merkle::root(&[leaf.clone()]);
+    assert_ne!(root, leaf);
+    assert_eq!(merkle::check_path(&merkle::path(&[leaf.clone()], 0), &leaf), (root, 1, 0));
+}
+
+#[test]
+fn generated_chain() {
+    let expected = hex::decode(FOUR_EMPTY_PASSES).unwrap();
+    for header in &testing::chain(4)[1..] {
+        assert_eq!(header.operations_hash(), &expected);
+    }
+}
+
+#[test]
+fn accept_operations() {
+    let headers = testing::chain(2);
+    let hash = block_hash(&headers[1]).unwrap();
+    let shared = SharedChain::from_headers(headers).unwrap();
+    let leaves = vec![merkle::root(&[]); testing::VALIDATION_PASSES as usize];
+    let message = |validation_pass: i8, path: usize, operations: Vec<Operation>| {
+        OperationsForBlocksMessage::new(
+            OperationsForBlock::new(hash.clone(), validation_pass),
+            merkle::path(&leaves, path),
+            operations,
+        )
+    };
+
+    // the path leads to another pass
+    assert!(shared.accept_operations(&message(0, 1, vec![])).is_err());
+    // the operations do not match the operations hash
+    let operation = Operation::from_bytes(vec![0; 40]).unwrap();
+    assert!(shared.accept_operations(&message(0, 0, vec![operation])).is_err());
+
+    for validation_pass in 0..testing::VALIDATION_PASSES {
+        assert!(!shared.is_operations_complete().unwrap());
+        let pass = validation_pass as usize;
+        shared
+            .accept_operations(&message(validation_pass as i8, pass, vec![]))
+            .unwrap();
+    }
+    assert!(shared.is_operations_complete().unwrap());
+}
+
+/// Three operations in the first pass, one in the second, the other two passes are empty,
+/// the hashes are computed independently of the implementation
+const PASS_0: &str = "4a863bc92d1df419295a86196cf3f64db8f6d70e7e28ad282722bf5fae2e094b";
+const PASS_1: &str = "312b13051120af2b6d9a0de3f86254ad957963be0863a320ee71da1dc051d410";
+const OPERATIONS_HASH: &str = "6186fbe5290943fe661b5277e1721dd9225d7cf75a5d7460b7d059d3ae9dfac5";
+
+fn operations() -> Vec<Operation> {
+    let data: [(u8, &[u8]); 3] = [(0x11, b"first"), (0x22, b"second"), (0x33, b"third")];
+    data.iter()
+        .map(|&(branch, data)| {
+            let mut bytes = vec![branch; 32];
+            bytes.extend_from_slice(data);
+            Operation::from_bytes(bytes).unwrap()
+        })
+        .collect()
+}
+
+/// The block with the `operations`, only the fields checked by the merkle tree matter
+fn block(operations_hash: Hash) -> BlockHeader {
+    BlockHeaderBuilder::default()
+        .level(1)
+        .proto(1)
+        .predecessor(vec![0; 32])
+        .timestamp(0)
+        .validation_pass(4)
+        .operations_hash(operations_hash)
+        .fitness(testing::default_fitness(1))
+        .context(vec![0; 32])
+        .protocol_data(vec![])
+        .build()
+        .unwrap()
+}
+
+fn passes(operations: &[Operation]) -> Vec<Hash> {
+    let empty = merkle::root(&[]);
+    vec![
+        merkle::operation_list_hash(operations).unwrap(),
+        merkle::operation_list_hash(&operations[..1]).unwrap(),
+        empty.clone(),
+        empty,
+    ]
+}
+
+#[test]
+fn operation_lists() {
+    let operations = operations();
+    let passes = passes(&operations);
+    assert_eq!(passes[0], hex::decode(PASS_0).unwrap());
+    assert_eq!(passes[1], hex::decode(PASS_1).unwrap());
+    assert_eq!(merkle::operations_hash(&passes), hex::decode(OPERATIONS_HASH).unwrap());
+}
+
+#[test]
+fn check_operations() {
+    let operations = operations();
+    let passes = passes(&operations);
+    let header = block(hex::decode(OPERATIONS_HASH).unwrap());
+    let hash = block_hash(&header).unwrap();
+    let message = |validation_pass: i8, path: usize, operations: Vec<Operation>| {
+        OperationsForBlocksMessage::new(
+            OperationsForBlock::new(hash.clone(), validation_pass),
+            merkle::path(&passes, path),
+            operations,
+        )
+    };
+
+    merkle::check_operations(&header, &message(0, 0, operations.clone())).unwrap();
+    merkle::check_operations(&header, &message(1, 1, operations[..1].to_vec())).unwrap();
+    // the path leads to another pass
+    assert!(merkle::check_operations(&header, &message(0, 1, operations.clone())).is_err());
+    // an operation is missing, or the order is changed
+    assert!(merkle::check_operations(&header, &message(0, 0, operations[1..].to_vec())).is_err());
+    let mut reversed = operations.clone();
+    reversed.reverse();
+    assert!(merkle::check_operations(&header, &message(0, 0, reversed)).is_err());
+}
+
+#[test]
+fn check_operation_hashes() {
+    let operations = operations();
+    let passes = passes(&operations);
+    let hashes = operations
+        .iter()
+        .map(|operation| merkle::operation_hash(operation).unwrap())
+        .collect::<Vec<_>>();
+    let header = block(hex::decode(OPERATIONS_HASH).unwrap());
+    let hash = block_hash(&header).unwrap();
+    let message = |path: &[Hash], hashes: Vec<Hash>| {
+        OperationHashesForBlocksMessage::new(
+            OperationHashesForBlock::new(hash.clone(), 0),
+            merkle::path(path, 0),
+            hashes,
+        )
+    };
+
+    merkle::check_operation_hashes(&header, &message(&passes, hashes.clone())).unwrap();
+    // a hash of the list is tampered
+    let mut tampered = hashes.clone();
+    tampered[1][0] ^= 0xff;
+    assert!(merkle::check_operation_hashes(&header, &message(&passes, tampered)).is_err());
+    // the sibling of the pass in the path is tampered
+    let mut tampered = passes.clone();
+    tampered[1][0] ^= 0xff;
+    assert!(merkle::check_operation_hashes(&header, &message(&tampered, hashes.clone())).is_err());
+
+    // the chain checks the hashes of the known block only
+    let shared = SharedChain::from_headers(testing::chain(2)).unwrap();
+    let unknown = message(&passes, hashes.clone());
+    shared.check_operation_hashes(&unknown).unwrap();
+}
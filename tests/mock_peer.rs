@@ -0,0 +1,521 @@
+use std::time::Duration;
+use tezedge_bootstrap_poc::{
+    Socket, SocketError, SyncError, Config, SharedChain, HeaderStore, TrustedCheckpoint,
+    MempoolObserver, block_hash,
+    testing::{self, MockPeer, Fault, ChainGenerator},
+};
+
+#[tokio::test]
+async fn sync_headers() {
+    let (address, peer) = MockPeer::new(testing::chain(32)).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn nack() {
+    let headers = testing::chain(4);
+    let peer = MockPeer::new(headers.clone()).fault(Fault::Nack);
+    let (address, _) = peer.spawn().await.unwrap();
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    // the handshake ends without an error, but the peer is dropped
+    socket.run(&testing::logger()).await.unwrap();
+    assert!(shared.public_peers().is_empty());
+    assert!(!shared.contains(&block_hash(&headers[3]).unwrap()));
+    assert!(!shared.is_complete());
+    assert!(shared.current_branch(1).is_none());
+}
+
+#[tokio::test]
+async fn wrong_chain_id() {
+    let headers = testing::chain(4);
+    let peer = MockPeer::new(headers.clone()).fault(Fault::WrongChainId);
+    let (address, _) = peer.spawn().await.unwrap();
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    // the branch of another chain is ignored, the peer is dropped
+    socket.run(&testing::logger()).await.unwrap();
+    assert!(shared.public_peers().is_empty());
+    assert!(!shared.contains(&block_hash(&headers[3]).unwrap()));
+    assert!(!shared.is_complete());
+    assert!(shared.current_branch(1).is_none());
+}
+
+#[tokio::test]
+async fn truncated_chunk() {
+    let peer = MockPeer::new(testing::chain(8)).fault(Fault::TruncatedChunk(2));
+    let (address, _) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    assert!(socket.run(&testing::logger()).await.is_err());
+}
+
+#[tokio::test]
+async fn bad_mac() {
+    let peer = MockPeer::new(testing::chain(8)).fault(Fault::BadMac(2));
+    let (address, _) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    assert!(socket.run(&testing::logger()).await.is_err());
+}
+
+#[tokio::test]
+async fn bounded_requests() {
+    // the window is wider than a request might be, the mock peer refuses longer lists
+    let peer = MockPeer::new(testing::chain(100)).history(Vec::new());
+    let (address, peer) = peer.spawn().await.unwrap();
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_complete());
+    assert!(shared.is_operations_complete().unwrap());
+}
+
+#[tokio::test]
+async fn stall() {
+    let headers = testing::chain(32);
+    let config = Config {
+        request_timeout: Duration::from_millis(300),
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+
+    let peer = MockPeer::new(headers.clone()).fault(Fault::Stall(1, Duration::from_secs(60)));
+    let (address, _) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, config.clone(), shared.clone());
+    let stalled = tokio::spawn(async move { socket.run(&testing::logger()).await });
+    // let the stalled peer lease the gaps first
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let (address, _) = MockPeer::new(headers).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    let run = socket.run(&testing::logger());
+    tokio::time::timeout(Duration::from_secs(10), run)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(shared.is_complete());
+    match stalled.await.unwrap() {
+        Err(SocketError::Sync(SyncError::Timeout)) => (),
+        _ => panic!("the stalled peer must be dropped"),
+    }
+}
+
+#[tokio::test]
+async fn stall_operations() {
+    let headers = testing::chain(32);
+    let config = Config {
+        request_timeout: Duration::from_millis(300),
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+
+    // the only peer has the headers, but not the operations
+    let peer = MockPeer::new(headers.clone()).fault(Fault::IgnoreOperations);
+    let (address, _) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, config.clone(), shared.clone());
+    let run = socket.run(&testing::logger());
+    match tokio::time::timeout(Duration::from_secs(10), run).await.unwrap() {
+        Err(SocketError::Sync(SyncError::Timeout)) => (),
+        _ => panic!("the stalled peer must be dropped"),
+    }
+    assert!(shared.is_complete());
+    assert!(!shared.is_operations_complete().unwrap());
+
+    // the passes are released, another peer downloads them
+    let (address, _) = MockPeer::new(headers).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    let run = socket.run(&testing::logger());
+    tokio::time::timeout(Duration::from_secs(10), run)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(shared.is_operations_complete().unwrap());
+}
+
+#[tokio::test]
+async fn wrong_header() {
+    let peer = MockPeer::new(testing::chain(8)).fault(Fault::WrongHeader(1));
+    let (address, _) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    match socket.run(&testing::logger()).await {
+        Err(SocketError::Sync(SyncError::UnrequestedHeader { .. })) => (),
+        _ => panic!("the header must be rejected"),
+    }
+}
+
+#[tokio::test]
+async fn wrong_operations() {
+    let peer = MockPeer::new(testing::chain(8)).fault(Fault::WrongOperations);
+    let (address, _) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    match socket.run(&testing::logger()).await {
+        Err(SocketError::Sync(SyncError::OperationsHash { .. })) => (),
+        _ => panic!("the operations must be rejected"),
+    }
+}
+
+#[tokio::test]
+async fn fetch_protocol() {
+    let (hash, protocol) = testing::protocol("let x = 1");
+    let peer = MockPeer::new(testing::chain(4)).protocol((hash.clone(), protocol));
+    let (address, peer) = peer.spawn().await.unwrap();
+    let config = Config {
+        protocols: vec![hash],
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_protocols_complete());
+}
+
+#[tokio::test]
+async fn protocol_change() {
+    let (hash, protocol) = testing::protocol("let x = 2");
+    let headers = ChainGenerator::new(8).protocol_change(5).generate();
+    let peer = MockPeer::new(headers).protocol((hash.clone(), protocol));
+    let (address, peer) = peer.spawn().await.unwrap();
+    let config = Config {
+        activations: vec![(2, hash)],
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_protocols_complete());
+    assert!(shared.unknown_activations().is_empty());
+}
+
+#[tokio::test]
+async fn missing_protocol() {
+    let (hash, protocol) = testing::protocol("let x = 2");
+    let headers = ChainGenerator::new(8).protocol_change(5).generate();
+    let config = Config {
+        request_timeout: Duration::from_millis(300),
+        activations: vec![(2, hash.clone())],
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+
+    // the peer without the protocol is not failed, the protocol is left to other peers
+    let (address, _) = MockPeer::new(headers.clone()).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, config.clone(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    assert!(shared.is_complete());
+    assert!(!shared.is_protocols_complete());
+
+    let peer = MockPeer::new(headers).protocol((hash, protocol));
+    let (address, peer) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_protocols_complete());
+}
+
+#[tokio::test]
+async fn follow_head() {
+    let headers = testing::chain(32);
+    let head = block_hash(headers.last().unwrap()).unwrap();
+    let peer = MockPeer::new(headers).branch_head(20);
+    let (address, _) = peer.spawn().await.unwrap();
+    let config = Config {
+        follow: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    tokio::spawn(async move { socket.run(&testing::logger()).await });
+    for _ in 0..50 {
+        if shared.contains(&head) && shared.is_operations_complete().unwrap() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the new head must be downloaded");
+}
+
+#[tokio::test]
+async fn observe_mempool() {
+    let headers = testing::chain(8);
+    let branch = block_hash(headers.last().unwrap()).unwrap();
+    let (hash, operation) = testing::operation(&branch, b"transfer");
+    let peer = MockPeer::new(headers).mempool_operation((hash.clone(), operation));
+    let (address, _) = peer.spawn().await.unwrap();
+    let config = Config {
+        follow: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let mempool = MempoolObserver::new(Duration::from_secs(60));
+    let shared = SharedChain::new().with_mempool(mempool.clone());
+    let (mut socket, _) = Socket::outgoing(address, config, shared);
+    tokio::spawn(async move { socket.run(&testing::logger()).await });
+    for _ in 0..50 {
+        let live = mempool.live();
+        if let [(observed_hash, observed)] = live.as_slice() {
+            if observed.operation.is_some() {
+                assert_eq!(observed_hash, &hash);
+                assert_eq!(observed.peer, address);
+                return;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the operation must be observed");
+}
+
+#[tokio::test]
+async fn advertise_public_peer() {
+    let (address, _) = MockPeer::new(testing::chain(8)).spawn().await.unwrap();
+    let config = Config {
+        follow: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    tokio::spawn(async move { socket.run(&testing::logger()).await });
+    for _ in 0..50 {
+        if shared.public_peers() == vec![address] {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the public peer must be known");
+}
+
+#[tokio::test]
+async fn private_peer() {
+    let peer = MockPeer::new(testing::chain(8)).private();
+    let (address, _) = peer.spawn().await.unwrap();
+    let config = Config {
+        follow: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let shared = SharedChain::new();
+    let (mut socket, _) = Socket::outgoing(address, config, shared.clone());
+    tokio::spawn(async move { socket.run(&testing::logger()).await });
+    for _ in 0..50 {
+        if shared.is_operations_complete().unwrap() {
+            assert!(shared.public_peers().is_empty());
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the chain must be downloaded");
+}
+
+#[tokio::test]
+async fn sync_from_two_peers() {
+    let headers = testing::chain(64);
+    let shared = SharedChain::new();
+    let mut sockets = Vec::new();
+    for _ in 0..2 {
+        let (address, _) = MockPeer::new(headers.clone()).spawn().await.unwrap();
+        let (socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+        sockets.push(socket);
+    }
+    for mut socket in sockets {
+        let logger = testing::logger();
+        tokio::spawn(async move { socket.run(&logger).await.unwrap() });
+    }
+    tokio::time::timeout(Duration::from_secs(10), shared.completed())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn empty_history() {
+    let peer = MockPeer::new(testing::chain(16)).history(Vec::new());
+    let (address, peer) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn short_history() {
+    let headers = testing::chain(16);
+    let history = vec![block_hash(&headers[7]).unwrap()];
+    let (address, peer) = MockPeer::new(headers).history(history).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn genesis_head() {
+    let (address, peer) = MockPeer::new(testing::chain(1)).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), SharedChain::new());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn sync_delta() {
+    let headers = testing::chain(32);
+    let shared = SharedChain::from_headers(headers[..20].to_vec()).unwrap();
+    let (address, peer) = MockPeer::new(headers).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_complete());
+}
+
+#[tokio::test]
+async fn resume() {
+    let path = std::env::temp_dir().join(format!("resume-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let headers = testing::chain(32);
+    let head = block_hash(headers.last().unwrap()).unwrap();
+    {
+        // the previous run has downloaded the upper part of the chain
+        let mut store = HeaderStore::open(&path, 8).unwrap();
+        for header in &headers[12..] {
+            store.append(header).unwrap();
+        }
+    }
+
+    let shared = SharedChain::from_store(HeaderStore::open(&path, 8).unwrap(), None).unwrap();
+    assert!(!shared.is_complete());
+    let (address, peer) = MockPeer::new(headers).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_complete());
+
+    let store = HeaderStore::open(&path, 8).unwrap();
+    assert_eq!(store.len(), 32);
+    assert_eq!(store.tip(), Some(&head));
+    assert!(store.checkpoint().is_none());
+}
+
+#[tokio::test]
+async fn resume_checkpoint() {
+    let path = std::env::temp_dir().join(format!("resume-checkpoint-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let headers = testing::chain(64);
+    let head = block_hash(headers.last().unwrap()).unwrap();
+    {
+        // the connection breaks in the middle of the sync
+        let shared = SharedChain::from_store(HeaderStore::open(&path, 8).unwrap(), None).unwrap();
+        let peer = MockPeer::new(headers.clone()).fault(Fault::TruncatedChunk(20));
+        let (address, _) = peer.spawn().await.unwrap();
+        let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+        assert!(socket.run(&testing::logger()).await.is_err());
+        assert!(!shared.is_complete());
+    }
+
+    let store = HeaderStore::open(&path, 8).unwrap();
+    let checkpoint = store.checkpoint().unwrap();
+    assert_eq!(checkpoint.head, head);
+    assert!(!checkpoint.anchors.is_empty());
+    assert!(store.len() > 2 && store.len() < 64);
+    drop(store);
+
+    // corrupt the hash of the second record, the first one is the head
+    let file = path.join("headers.dat");
+    let mut data = std::fs::read(&file).unwrap();
+    let first = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let offset = 4 + 32 + first + 4;
+    let corrupted = data[offset..(offset + 32)].to_vec();
+    data[offset] ^= 0xff;
+    std::fs::write(&file, &data).unwrap();
+
+    let store = HeaderStore::open(&path, 8).unwrap();
+    assert!(!store.contains(&corrupted));
+    let shared = SharedChain::from_store(store, None).unwrap();
+    assert!(!shared.is_complete());
+    let (address, peer) = MockPeer::new(headers).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+    assert!(shared.is_complete());
+    drop((socket, shared));
+
+    // the corrupted header is downloaded again
+    let store = HeaderStore::open(&path, 8).unwrap();
+    assert_eq!(store.len(), 64);
+    assert!(store.contains(&corrupted));
+    assert_eq!(store.tip(), Some(&head));
+    assert!(store.checkpoint().is_none());
+}
+
+#[tokio::test]
+async fn reorg() {
+    let local = testing::chain(20);
+    let fork = ChainGenerator::new(0).seed(1).fork(&local, 10, 15);
+    let shared = SharedChain::from_headers(local).unwrap();
+    let (address, peer) = MockPeer::new(fork.clone()).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+
+    let reorgs = shared.take_reorgs();
+    assert_eq!(reorgs.len(), 1);
+    assert_eq!(reorgs[0].new_head, block_hash(fork.last().unwrap()).unwrap());
+    // levels 11 to 19 of the local chain are replaced
+    assert_eq!(reorgs[0].depth, 9);
+}
+
+#[tokio::test]
+async fn checkpoint() {
+    let chain = testing::chain(32);
+    let checkpoint = TrustedCheckpoint {
+        hash: block_hash(&chain[16]).unwrap(),
+        level: 16,
+    };
+    let shared = SharedChain::new().with_checkpoint(checkpoint);
+    let (address, peer) = MockPeer::new(chain.clone()).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+
+    assert!(shared.is_complete());
+    assert!(shared.contains(&block_hash(&chain[16]).unwrap()));
+    // the history goes below the checkpoint, but nothing below it is requested
+    assert!(chain[..16].iter().all(|h| !shared.contains(&block_hash(h).unwrap())));
+}
+
+#[tokio::test]
+async fn checkpoint_in_history() {
+    let chain = testing::chain(32);
+    let checkpoint = TrustedCheckpoint {
+        hash: block_hash(&chain[16]).unwrap(),
+        level: 16,
+    };
+    let history = [28, 24, 16, 8, 4]
+        .iter()
+        .map(|&level| block_hash(&chain[level]).unwrap())
+        .collect();
+    let shared = SharedChain::new().with_checkpoint(checkpoint);
+    let peer = MockPeer::new(chain.clone()).history(history);
+    let (address, peer) = peer.spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared.clone());
+    socket.run(&testing::logger()).await.unwrap();
+    peer.await.unwrap().unwrap();
+
+    // the hashes above the checkpoint are anchors, those below it are skipped
+    assert!(shared.is_complete());
+    assert!(chain[16..].iter().all(|h| shared.contains(&block_hash(h).unwrap())));
+    assert!(chain[..16].iter().all(|h| !shared.contains(&block_hash(h).unwrap())));
+}
+
+#[tokio::test]
+async fn wrong_checkpoint() {
+    let local = testing::chain(32);
+    let fork = ChainGenerator::new(0).seed(1).fork(&local, 10, 32);
+    let checkpoint = TrustedCheckpoint {
+        hash: block_hash(&fork[16]).unwrap(),
+        level: 16,
+    };
+    let shared = SharedChain::new().with_checkpoint(checkpoint);
+    let (address, _) = MockPeer::new(local).spawn().await.unwrap();
+    let (mut socket, _) = Socket::outgoing(address, Config::default(), shared);
+    match socket.run(&testing::logger()).await {
+        Err(SocketError::Sync(SyncError::Checkpoint { level: 16 })) => (),
+        _ => panic!("the branch without the checkpoint must be rejected"),
+    }
+}
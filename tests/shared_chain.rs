@@ -0,0 +1,96 @@
+use std::{collections::HashSet, time::Duration};
+use tezos_messages::p2p::encoding::block_header::BlockHeader;
+use crypto::hash::Hash;
+use tezedge_bootstrap_poc::{
+    SharedChain, HeaderStore, TrustedCheckpoint, block_hash,
+    testing::{self, ChainGenerator},
+};
+
+fn anchor(header: &BlockHeader) -> (Hash, u32) {
+    (block_hash(header).unwrap(), header.level() as u32)
+}
+
+#[test]
+fn losing_branch_anchors() {
+    let headers = testing::chain(16);
+    let fork = ChainGenerator::new(0).seed(1).fork(&headers, 8, 4);
+    let shared = SharedChain::new();
+    // the fork is announced first, its anchor is never downloaded
+    shared
+        .accept_head(fork.last().unwrap(), vec![anchor(&fork[10])])
+        .unwrap();
+    shared
+        .accept_head(headers.last().unwrap(), vec![anchor(&headers[8])])
+        .unwrap();
+    assert!(!shared.is_complete());
+    for header in headers.iter().rev() {
+        shared.accept(header).unwrap();
+    }
+    assert!(shared.is_complete());
+}
+
+/// Blocks with passes to download, the leases are released right away
+fn pending_operations(shared: &SharedChain) -> HashSet<Hash> {
+    let passes = shared
+        .claim_operations(1, usize::MAX, Duration::from_secs(60))
+        .unwrap();
+    shared.release_operations(1, passes.iter());
+    passes.into_iter().map(|(hash, _)| hash).collect()
+}
+
+#[test]
+fn canonical_operations() {
+    let headers = testing::chain(16);
+    let hashes = headers
+        .iter()
+        .map(|header| block_hash(header).unwrap())
+        .collect::<Vec<_>>();
+    let shared = SharedChain::from_headers(headers.clone()).unwrap();
+    assert!(pending_operations(&shared).contains(&hashes[15]));
+
+    // the fork is less fit, its blocks are not canonical
+    let fork = ChainGenerator::new(0).seed(1).fork(&headers, 8, 4);
+    shared.accept_head(fork.last().unwrap(), vec![]).unwrap();
+    for header in fork[9..12].iter().rev() {
+        shared.accept(header).unwrap();
+    }
+    let pending = pending_operations(&shared);
+    assert!(fork[9..].iter().all(|h| !pending.contains(&block_hash(h).unwrap())));
+
+    // the fitter fork takes over, nothing is leased until it is complete
+    let fitter = ChainGenerator::new(0).seed(2).fork(&headers, 12, 8);
+    shared.accept_head(fitter.last().unwrap(), vec![]).unwrap();
+    assert!(pending_operations(&shared).is_empty());
+    for header in fitter[13..fitter.len() - 1].iter().rev() {
+        shared.accept(header).unwrap();
+    }
+    assert!(shared.is_complete());
+    let pending = pending_operations(&shared);
+    assert!(fitter[13..].iter().all(|h| pending.contains(&block_hash(h).unwrap())));
+    assert!(hashes[13..].iter().all(|hash| !pending.contains(hash)));
+    assert!(hashes[1..13].iter().all(|hash| pending.contains(hash)));
+}
+
+#[test]
+fn stored_headers_checkpoint() {
+    let path = std::env::temp_dir().join(format!("stored-checkpoint-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let headers = testing::chain(8);
+    let fork = ChainGenerator::new(0).seed(1).fork(&headers, 3, 4);
+    {
+        // the previous run has stored a branch without the checkpoint
+        let mut store = HeaderStore::open(&path, 1).unwrap();
+        for header in &fork {
+            store.append(header).unwrap();
+        }
+    }
+    let checkpoint = TrustedCheckpoint {
+        hash: block_hash(&headers[4]).unwrap(),
+        level: 4,
+    };
+    let store = HeaderStore::open(&path, 1).unwrap();
+    let shared = SharedChain::from_store(store, Some(checkpoint)).unwrap();
+    assert!(!shared.contains(&block_hash(&fork[4]).unwrap()));
+    assert!(shared.contains(&block_hash(&fork[3]).unwrap()));
+    assert!(!shared.is_complete());
+}
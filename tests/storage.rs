@@ -0,0 +1,156 @@
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        operation::Operation,
+        operations_for_blocks::{OperationsForBlock, OperationsForBlocksMessage},
+    },
+};
+use tezedge_bootstrap_poc::{HeaderStore, block_hash, merkle, testing};
+
+fn directory(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("header-store-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+#[test]
+fn reopen() {
+    let path = directory("reopen");
+    let headers = testing::chain(10);
+    let head = block_hash(headers.last().unwrap()).unwrap();
+    {
+        let mut store = HeaderStore::open(&path, 4).unwrap();
+        for header in &headers {
+            assert!(store.append(header).unwrap());
+        }
+        assert!(!store.append(&headers[3]).unwrap());
+        store.set_tip(&head).unwrap();
+    }
+
+    let store = HeaderStore::open(&path, 4).unwrap();
+    assert_eq!(store.len(), 10);
+    assert_eq!(store.tip(), Some(&head));
+    assert_eq!(store.level(&head), Some(9));
+    let stored = store
+        .range(2..5)
+        .map(|header| block_hash(&header.unwrap()).unwrap())
+        .collect::<Vec<_>>();
+    let expected = headers[2..5]
+        .iter()
+        .map(|header| block_hash(header).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(stored, expected);
+}
+
+#[test]
+fn torn_record() {
+    let path = directory("torn");
+    let headers = testing::chain(3);
+    {
+        let mut store = HeaderStore::open(&path, 1).unwrap();
+        for header in &headers {
+            store.append(header).unwrap();
+        }
+    }
+    // the process died in the middle of the next record
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(path.join("headers.dat"))
+        .unwrap();
+    file.write_all(&[0, 0, 1, 0, 0xaa, 0xbb]).unwrap();
+
+    let mut store = HeaderStore::open(&path, 1).unwrap();
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.tip(), None);
+    let extra = testing::chain(4).pop().unwrap();
+    assert!(store.append(&extra).unwrap());
+    drop(store);
+
+    let store = HeaderStore::open(&path, 1).unwrap();
+    assert_eq!(store.len(), 4);
+    let hash = block_hash(&extra).unwrap();
+    let stored = store.get(&hash).unwrap().unwrap();
+    assert_eq!(block_hash(&stored).unwrap(), hash);
+}
+
+#[test]
+fn corrupted_record() {
+    let path = directory("corrupted");
+    let headers = testing::chain(4);
+    {
+        let mut store = HeaderStore::open(&path, 1).unwrap();
+        for header in &headers {
+            store.append(header).unwrap();
+        }
+    }
+    // flip a byte of the hash of the second record
+    let mut data = std::fs::read(path.join("headers.dat")).unwrap();
+    let first = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    data[4 + 32 + first + 4] ^= 0xff;
+    std::fs::write(path.join("headers.dat"), &data).unwrap();
+
+    // only the corrupted record is skipped, the records after it are kept
+    let mut store = HeaderStore::open(&path, 1).unwrap();
+    assert_eq!(store.len(), 3);
+    assert!(!store.contains(&block_hash(&headers[1]).unwrap()));
+    assert!(store.contains(&block_hash(&headers[3]).unwrap()));
+    assert!(store.append(&headers[1]).unwrap());
+    drop(store);
+
+    let store = HeaderStore::open(&path, 1).unwrap();
+    assert_eq!(store.len(), 4);
+    let stored = store.get(&block_hash(&headers[1]).unwrap()).unwrap().unwrap();
+    assert_eq!(stored.level(), 1);
+}
+
+#[test]
+fn forget() {
+    let path = directory("forget");
+    let headers = testing::chain(4);
+    let hash = block_hash(&headers[2]).unwrap();
+    {
+        let mut store = HeaderStore::open(&path, 1).unwrap();
+        for header in &headers {
+            store.append(header).unwrap();
+        }
+        store.forget(&hash);
+        assert!(!store.contains(&hash));
+        assert!(store.hashes_at(2).is_empty());
+        assert_eq!(store.range(..).count(), 3);
+        assert!(store.append(&headers[2]).unwrap());
+    }
+
+    // both records are on the disk, the header is indexed once
+    let store = HeaderStore::open(&path, 1).unwrap();
+    assert_eq!(store.len(), 4);
+    assert_eq!(store.hashes_at(2), &[hash][..]);
+}
+
+#[test]
+fn operation_index() {
+    let path = directory("operations");
+    let headers = testing::chain(2);
+    let hash = block_hash(&headers[1]).unwrap();
+    let mut bytes = hash.clone();
+    bytes.extend_from_slice(b"operation");
+    let operation = Operation::from_bytes(bytes).unwrap();
+    let operation_hash = merkle::operation_hash(&operation).unwrap();
+    {
+        let mut store = HeaderStore::open(&path, 1).unwrap();
+        store.append(&headers[1]).unwrap();
+        let leaves = vec![merkle::operation_list_hash(&[operation.clone()]).unwrap()];
+        let operations = OperationsForBlocksMessage::new(
+            OperationsForBlock::new(hash.clone(), 0),
+            merkle::path(&leaves, 0),
+            vec![operation],
+        );
+        assert!(store.append_operations(&operations).unwrap());
+    }
+
+    let store = HeaderStore::open(&path, 1).unwrap();
+    assert!(store.contains_operations(&hash, 0));
+    let stored = store.operation(&operation_hash).unwrap().unwrap();
+    assert_eq!(merkle::operation_hash(&stored).unwrap(), operation_hash);
+    assert!(store.operation(&hash).unwrap().is_none());
+}